@@ -1,4 +1,8 @@
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+use mqtt_playground::connection::{Backoff, CredentialArgs, MqttVersion, SessionConfig, SessionMode, TlsArgs};
+use mqtt_playground::payload::PayloadKind;
+use mqtt_playground::profile::LoadProfile;
+use mqtt_playground::fault::{FaultSpec, FaultTarget};
 use lazy_static::lazy_static;
 
 lazy_static! {
@@ -11,6 +15,22 @@ pub struct CliArgs {
     #[arg(short='t', long="TARGET", default_value="mqtt://localhost:1883")]
     pub target_host_uri: String,
 
+    // Sweep plan file (TOML) listing the iterations, overrides the matching sweep flags
+    #[arg(long="PLAN")]
+    pub plan: Option<String>,
+
+    // Completed iterations are saved to this file after each iteration
+    #[arg(long="STATE_FILE", default_value="./Logs/analyser-state.json")]
+    pub state_file: String,
+
+    // Pick up the sweep saved in --STATE_FILE, skipping the iterations it already completed
+    #[arg(long="RESUME", default_value="false")]
+    pub resume: bool,
+
+    // Start a new sweep over an existing --STATE_FILE, discarding the iterations saved in it
+    #[arg(long="OVERWRITE_STATE", default_value="false", conflicts_with="resume")]
+    pub overwrite_state: bool,
+
     // Delay level as in delay = 2 ^ (delay_level - 1)
     #[arg(short='d', long="DMIN", default_value="0")]
     pub delay_level_min: u64,
@@ -19,6 +39,59 @@ pub struct CliArgs {
     #[arg(short='D', long="DMAX", default_value="5")]
     pub delay_level_max: u64,
 
+    // Messages per second per publisher client, e.g. "100,1000,10000" runs every iteration
+    // once per rate instead of once per delay level
+    #[arg(long="RATES", value_delimiter=',', conflicts_with="profiles")]
+    pub rates: Vec<f64>,
+
+    // Load profile of every publisher client, repeat to run every iteration once per profile
+    // instead of once per delay level: constant:<rate>, ramp:<from>:<to>:<duration s>,
+    // step:<rate,rate,...>:<step s>, sine:<mean>:<amplitude>:<period s> or burst:<size>:<every s>
+    #[arg(long="PROFILE")]
+    pub profiles: Vec<LoadProfile>,
+
+    // Late messages further than this many sequence numbers behind the highest one received
+    // are counted as late instead of reordered
+    #[arg(long="REORDER_WINDOW", default_value="1000")]
+    pub reorder_window: u64,
+
+    // Width of the subscriber timeline buckets, in milliseconds
+    #[arg(long="TIMELINE_MS", default_value="1000")]
+    pub timeline_ms: u64,
+
+    // Search the highest sustainable rate of every qos/instance count instead of sweeping
+    // fixed delay levels or rates
+    #[arg(long="SATURATE", default_value="false", conflicts_with_all=["rates", "profiles"])]
+    pub saturate: bool,
+
+    // First rate probed by the saturation search, in messages per second per publisher client
+    #[arg(long="SAT_START_RATE", default_value="100")]
+    pub sat_start_rate: f64,
+
+    // Rate multiplier between probes until the first one fails
+    #[arg(long="SAT_FACTOR", default_value="2")]
+    pub sat_factor: f64,
+
+    // Rate the search gives up at, in messages per second per publisher client
+    #[arg(long="SAT_MAX_RATE", default_value="1000000")]
+    pub sat_max_rate: f64,
+
+    // A probe fails when the fraction of lost messages exceeds this
+    #[arg(long="SAT_MAX_LOSS", default_value="0.001")]
+    pub sat_max_loss: f64,
+
+    // A probe fails when the p99 latency exceeds this, in milliseconds
+    #[arg(long="SAT_MAX_P99_MS", default_value="100")]
+    pub sat_max_p99_ms: f64,
+
+    // A probe fails when the publishers achieve less than this fraction of the target rate
+    #[arg(long="SAT_MIN_ACHIEVED", default_value="0.9")]
+    pub sat_min_achieved: f64,
+
+    // The binary search stops once the bracket is narrower than this fraction of its upper rate
+    #[arg(long="SAT_PRECISION", default_value="0.05")]
+    pub sat_precision: f64,
+
     // Maximum number of instances count
     #[arg(short='i', long="IMIN", default_value="1")]
     pub instancecount_min: usize,
@@ -34,4 +107,151 @@ pub struct CliArgs {
     // Buffering period in-between each iteration
     #[arg(short='r', long="R", default_value="10")]
     pub reset_buffer: u64,
+
+    // How long to wait for the controller to acknowledge a command, in seconds
+    #[arg(long="READY_TIMEOUT", default_value="30")]
+    pub ready_timeout: u64,
+
+    // Minimum number of controllers required to start the sweep
+    #[arg(short='c', long="CONTROLLERS", default_value="1")]
+    pub min_controllers: usize,
+
+    // Time given to controllers to register before the sweep starts, in seconds
+    #[arg(long="DISCOVERY", default_value="3")]
+    pub discovery: u64,
+
+    // Controller is considered absent after this many seconds without a heartbeat
+    #[arg(long="HEARTBEAT_TIMEOUT", default_value="10")]
+    pub heartbeat_timeout: u64,
+
+    // How long to wait for an absent controller before aborting the sweep, in seconds
+    #[arg(long="PAUSE_TIMEOUT", default_value="300")]
+    pub pause_timeout: u64,
+
+    #[command(flatten)]
+    pub creds: CredentialArgs,
+
+    #[command(flatten)]
+    pub tls: TlsArgs,
+
+    // Run every iteration over both the plain target and the TLS target
+    #[arg(long="TLS_SWEEP", default_value="false")]
+    pub tls_sweep: bool,
+
+    // Protocol version of the control channel and of every iteration
+    #[arg(long="MQTT_VERSION", value_enum, default_value="3.1.1")]
+    pub mqtt_version: MqttVersion,
+
+    // Run every iteration over both MQTT v3.1.1 and MQTT v5
+    #[arg(long="VERSION_SWEEP", default_value="false")]
+    pub version_sweep: bool,
+
+    // Subscribers join the shared subscription "$share/<group>/..." over all publisher topics
+    // instead of pairing up with one publisher each
+    #[arg(long="SHARED", conflicts_with_all=["fan_outs", "fan_in"])]
+    pub shared_group: Option<String>,
+
+    // Number of shared subscription members, defaults to the iteration's instance count
+    #[arg(long="MEMBERS")]
+    pub shared_members: Option<usize>,
+
+    // Subscribers per publisher topic, e.g. "1,2,4,8" runs every iteration once per fan-out
+    #[arg(long="FANOUT", value_delimiter=',')]
+    pub fan_outs: Vec<usize>,
+
+    // With --FANOUT, every subscriber subscribes to all publisher topics through "counter/+/..."
+    // so the fan-out is the number of subscribers
+    #[arg(long="WILDCARD", default_value="false", requires="fan_outs")]
+    pub wildcard: bool,
+
+    // A single subscriber consumes all publisher topics through "counter/#"
+    #[arg(long="FANIN", default_value="false", conflicts_with="fan_outs")]
+    pub fan_in: bool,
+
+    // Message body sizes in bytes, on top of the sequence header, e.g. "0,1024,1048576"
+    // runs every iteration once per size
+    #[arg(long="PAYLOAD_SIZES", value_delimiter=',', default_value="0")]
+    pub payload_sizes: Vec<usize>,
+
+    // Content of the message body
+    #[arg(long="PAYLOAD", value_enum, default_value="random")]
+    pub payload_kind: PayloadKind,
+
+    // Publish with the retain flag
+    #[arg(long="RETAIN", default_value="false")]
+    pub retain: bool,
+
+    // Session modes of the publisher and subscriber clients, e.g. "clean,persistent" runs every
+    // iteration once per mode
+    #[arg(long="SESSIONS", value_enum, value_delimiter=',', default_value="clean")]
+    pub sessions: Vec<SessionMode>,
+
+    // Clients that lose their connection reconnect automatically
+    #[arg(long="RECONNECT", default_value="false")]
+    pub reconnect: bool,
+
+    // First reconnect retry interval, doubling up to --RECONNECT_MAX_S, in seconds
+    #[arg(long="RECONNECT_MIN_S", default_value="1")]
+    pub reconnect_min_s: u64,
+
+    #[arg(long="RECONNECT_MAX_S", default_value="30")]
+    pub reconnect_max_s: u64,
+
+    // How long the broker keeps a persistent session after the connection is lost, in seconds (MQTT v5)
+    #[arg(long="SESSION_EXPIRY_S", default_value="300")]
+    pub session_expiry_s: u32,
+
+    // Fault injected into every iteration, repeat for several: publishers:<fraction>:<at s>:<down s>[:<every s>]
+    // and subscribers:<fraction>:<at s>:<down s>[:<every s>] disconnect that fraction of the clients for the
+    // down time, broker:<at s>[:<every s>] runs --BROKER_RESTART_CMD
+    #[arg(long="FAULT")]
+    pub faults: Vec<FaultSpec>,
+
+    // Messages sent this long before and after a fault are attributed to it in the report, in seconds
+    #[arg(long="FAULT_MARGIN_S", default_value="2")]
+    pub fault_margin_s: u64,
+
+    // Shell command restarting the broker for broker faults, e.g. "docker restart mosquitto"
+    #[arg(long="BROKER_RESTART_CMD")]
+    pub broker_restart_cmd: Option<String>,
+
+    // Skip collecting broker metrics from $SYS/#
+    #[arg(long="NO_SYS", default_value="false")]
+    pub no_sys: bool,
+
+    // Machine-readable results export written next to the log file
+    #[arg(short='e', long="EXPORT", value_enum, default_value="none")]
+    pub export_format: ExportFormat,
+
+    // Directory the exported results are written to
+    #[arg(long="EXPORT_DIR", default_value="./Logs/")]
+    pub export_dir: String,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    None,
+    Csv,
+    Json,
+    All,
+}
+
+impl CliArgs {
+    pub fn fault_specs(&self, target: FaultTarget) -> Vec<FaultSpec> {
+        return self.faults.iter().filter(|x| x.target == target).cloned().collect()
+    }
+
+    pub fn session_config(&self, mode: SessionMode) -> SessionConfig {
+        return SessionConfig {
+            mode: mode,
+            reconnect: if self.reconnect { Some(Backoff { min_s: self.reconnect_min_s, max_s: self.reconnect_max_s }) } else { None },
+            session_expiry_s: self.session_expiry_s,
+        }
+    }
+}
+
+impl ExportFormat {
+    pub fn csv(&self) -> bool { return matches!(self, ExportFormat::Csv | ExportFormat::All) }
+
+    pub fn json(&self) -> bool { return matches!(self, ExportFormat::Json | ExportFormat::All) }
 }
\ No newline at end of file
@@ -1,16 +1,29 @@
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::time::sleep;
-use paho_mqtt::{AsyncReceiver, QOS_1, QOS_2};
-use paho_mqtt::{AsyncClient, client, CreateOptionsBuilder, ConnectOptionsBuilder, Message, Client};
-use tokio::{runtime::{Runtime, Builder}, spawn, sync::Mutex};
+use paho_mqtt::AsyncReceiver;
+use paho_mqtt::{AsyncClient, Message};
+use tokio::runtime::{Runtime, Builder};
 
+mod checkpoint;
 mod cli_args;
+mod plan;
+mod saturation;
+mod scenario;
+mod session;
 
 use mqtt_playground::log::{self, set_tag};
 use mqtt_playground::write_log;
-use mqtt_playground::subscriber::{Subscriber, SysSubscriber};
-use cli_args::CLI_ARGS;
+use mqtt_playground::subscriber::SysSubscriber;
+use mqtt_playground::connection::ConnectionConfig;
+use mqtt_playground::stats::{RunResult, SaturationResult, results_table, saturation_table};
+use mqtt_playground::report::{Report, RunMetadata, write_export};
+use mqtt_playground::command::{ControllerMonitor, HEARTBEAT_TOPIC_FILTER, RESPONSE_TOPIC_FILTER};
+use mqtt_playground::fault::FaultTarget;
+use chrono::{DateTime, Utc};
+use checkpoint::{Checkpoint, SweepSettings};
+use cli_args::{CLI_ARGS, ExportFormat};
+use plan::Plan;
+use session::{IterationError, Session};
 
 fn main() {
     let main_rt = Builder::new_multi_thread()
@@ -20,105 +33,187 @@ fn main() {
 
     set_tag("A");
 
-    let mut subscriber: Option<Subscriber> = None;
-    let mut graceful_stop: Option<Arc<Mutex<bool>>> = None;
+    let mut results: Vec<RunResult> = Vec::new();
+    let mut saturations: Vec<SaturationResult> = Vec::new();
 
     let host_uri = &CLI_ARGS.target_host_uri;
-
-    write_log!("Starting analyser client... [host uri={}]\n", host_uri);
+    let creds = match CLI_ARGS.creds.resolve() {
+        Ok(creds) => creds,
+        Err(msg) => {
+            write_log!("Invalid credentials, aborting. [reason={}]\n", msg);
+            return
+        }
+    };
+    let connection = ConnectionConfig::new(host_uri, creds, &CLI_ARGS.tls, CLI_ARGS.mqtt_version);
+    let scenarios = match CLI_ARGS.plan.as_ref() {
+        Some(path) => match Plan::load(path).and_then(|plan| plan.scenarios(&CLI_ARGS, connection.is_tls())) {
+            Ok(scenarios) => scenarios,
+            Err(msg) => {
+                write_log!("Invalid sweep plan, aborting. [reason={}]\n", msg);
+                return
+            }
+        },
+        None => scenario::from_cli_args(&CLI_ARGS, connection.is_tls()),
+    };
+    let scenarios = if CLI_ARGS.saturate { scenario::saturation_bases(scenarios) } else { scenarios };
+    if CLI_ARGS.saturate {
+        if let Err(msg) = saturation::validate(&CLI_ARGS) {
+            write_log!("Invalid saturation search, aborting. [reason={}]\n", msg);
+            return
+        }
+    }
+    if scenarios.iter().any(|x| x.tls) {
+        if let Err(msg) = connection.to_tls() {
+            write_log!("TLS iterations planned but no TLS target available, aborting. [reason={}]\n", msg);
+            return
+        }
+    }
+    if !CLI_ARGS.fault_specs(FaultTarget::Broker).is_empty() && CLI_ARGS.broker_restart_cmd.is_none() {
+        write_log!("Broker faults need --BROKER_RESTART_CMD, aborting.\n");
+        return
+    }
+
+    write_log!("Starting analyser client... [host uri={}] [tls={}] [auth={}]\n", 
+        connection.display_uri(), connection.is_tls(), connection.describe_auth());
+
+    let checkpoint = match Checkpoint::open(
+        &CLI_ARGS.state_file, CLI_ARGS.resume, CLI_ARGS.overwrite_state, Utc::now(),
+        SweepSettings::from_cli_args(&CLI_ARGS, &connection)
+    ) {
+        Ok(checkpoint) => checkpoint,
+        Err(msg) => {
+            write_log!("Sweep state unusable, refusing to start. [reason={}]\n", msg);
+            return
+        }
+    };
+    if CLI_ARGS.resume {
+        write_log!("Resuming sweep. [state file={}] [completed iterations={}] [started at={}]\n",
+            checkpoint.path(), checkpoint.n_runs(), checkpoint.started_at().to_rfc3339());
+    }
+    let sweep_started_at = checkpoint.started_at();
 
     let mut analyser_client = AsyncClient::new(
-        CreateOptionsBuilder::new()
-        .client_id("analyser")
-        .server_uri(host_uri)
-        .finalize()
+        connection.create_options("analyser")
     ).unwrap();
 
     main_rt.block_on(async {
+        let resp_stream: AsyncReceiver<Option<Message>> = analyser_client.get_stream(32);
 
+        // the control channel has to outlive broker restarts
         analyser_client.connect(
-            ConnectOptionsBuilder::new()
-            .user_name("user")
-            .password("123")
+            connection.connect_options().unwrap()
+            .automatic_reconnect(Duration::from_secs(1), Duration::from_secs(30))
             .finalize()
         ).await.unwrap();
 
-        // let mut sys_subscriber = SysSubscriber::connect(&host_uri, None).await.unwrap();
-
-        // let sys_subscriber_handle = main_rt.spawn(async move {
-        //     sys_subscriber.start().await;
-        // });
-
-        for delay_exp in CLI_ARGS.delay_level_min..(CLI_ARGS.delay_level_max+1) {
-            let delay = if delay_exp == 0 { 0 } 
-                else { (2 as u64).pow((delay_exp - 1) as u32) };
-            analyser_client.publish(
-                Message::new("request/delay", delay.to_string(), QOS_2)
-            ).await.unwrap();
-
-            for qos in 0..3 {
-                analyser_client.publish(
-                    Message::new("request/qos", qos.to_string(), QOS_2)
-                ).await.unwrap();
-
-                for instancecount in CLI_ARGS.instancecount_min..(CLI_ARGS.instancecount_max+1) {
-                    analyser_client.publish(
-                        Message::new("request/instancecount", instancecount.to_string(), QOS_2)
-                    ).await.unwrap();
-
-                    println!(r#"Parameters updated. [
-    qos={},
-    delay={},
-    instancecount={},
-]
-                    "#, qos, delay, instancecount);
-
-                    analyser_client.publish(
-                        Message::new("request/reset", String::new(), QOS_2)
-                    ).await.unwrap();
-
-                    if let Ok(new_subscriber) = Subscriber::connect(
-                        host_uri, Some(("user", "123")), 
-                        instancecount, delay, qos
-                    ).await {
-                        subscriber = Some(new_subscriber);
-                        graceful_stop = Some(Arc::clone(&(subscriber.as_ref().unwrap()).make_graceful_stop));
-            
-                        write_log!("Starting new subscriber clients...\n");
-                
-                        let subscriber_handle = main_rt.spawn(async move {
-                            let stats = subscriber.unwrap().start().await;
-                            let total_n_messages = stats.iter().map(|x| {x.2}).sum::<u64>();
-                            write_log!(r#"Subscriber clients 0..{} terminated. [
-    total number of messages received={}
-]
-                            "#, instancecount, total_n_messages);
-                        });
-                    }
-
-                    sleep(Duration::from_secs(CLI_ARGS.mrt)).await;
-
-                    analyser_client.publish(
-                        Message::new("request/reset", String::new(), QOS_2)
-                    ).await.unwrap();
-
-                    if graceful_stop.is_some() {
-                        write_log!("Terminating subscriber clients...\n");
-                        *graceful_stop.as_mut().unwrap().lock().await = true;
-                    }
-
-                    sleep(Duration::from_secs(CLI_ARGS.reset_buffer)).await;
-
-                    subscriber = None;
-                    graceful_stop = None;
+        analyser_client.subscribe_many(&[RESPONSE_TOPIC_FILTER, HEARTBEAT_TOPIC_FILTER], &[2, 1]).await.unwrap();
+        analyser_client.set_connected_callback(|client| {
+            let _ = client.subscribe_many(&[RESPONSE_TOPIC_FILTER, HEARTBEAT_TOPIC_FILTER], &[2, 1]);
+        });
+
+        let mut monitor = ControllerMonitor::new(Duration::from_secs(CLI_ARGS.heartbeat_timeout));
+        if let Err(msg) = monitor.await_alive(
+            &resp_stream, CLI_ARGS.min_controllers, Duration::from_secs(CLI_ARGS.heartbeat_timeout)
+        ).await {
+            write_log!("Controller not available, refusing to start the sweep. [reason={}]\n", msg);
+            return
+        }
+        // give the remaining controllers a chance to register
+        let _ = monitor.watch(&resp_stream, &[], Duration::from_secs(CLI_ARGS.discovery)).await;
+        write_log!("Controllers discovered. [ids={:?}]\n", monitor.alive_controllers());
+
+        let mut broker_metrics = None;
+        let mut sys_graceful_stop = None;
+        let mut sys_subscriber_handle = None;
+        if !CLI_ARGS.no_sys {
+            match SysSubscriber::connect(&connection).await {
+                Ok(mut sys_subscriber) => {
+                    broker_metrics = Some(Arc::clone(&sys_subscriber.metrics));
+                    sys_graceful_stop = Some(Arc::clone(&sys_subscriber.make_graceful_stop));
+                    sys_subscriber_handle = Some(main_rt.spawn(async move {
+                        sys_subscriber.start().await;
+                    }));
+                }
+                Err(msg) => {
+                    write_log!("SYS subscriber unavailable, broker metrics will be skipped. [reason={}]\n", msg);
                 }
             }
         }
-        
-        analyser_client.publish(
-            Message::new("request/killall", String::new(), QOS_2)
-        ).await.unwrap();
+
+        let mut session = Session::new(
+            connection.clone(), analyser_client, resp_stream, monitor, broker_metrics, checkpoint
+        );
+        if CLI_ARGS.saturate {
+            for base in scenarios {
+                match saturation::search(&mut session, &base, &mut results).await {
+                    Ok(saturation) => saturations.push(saturation),
+                    Err(_) => break,
+                }
+            }
+        } else {
+            for scenario in scenarios {
+                match session.run_scenario(&scenario).await {
+                    Ok(result) => results.push(result),
+                    Err(IterationError::Skipped(_)) => {}
+                    Err(IterationError::SweepAborted(_)) => break,
+                }
+            }
+        }
+
+        session.kill_all().await;
+
+        if let (Some(stop), Some(handle)) = (sys_graceful_stop, sys_subscriber_handle) {
+            *stop.lock().await = true;
+            let _ = handle.await;
+        }
+
+        write_log!("Sweep finished, results:\n{}", results_table(&results));
+        if !saturations.is_empty() {
+            write_log!("Saturation search finished, knees:\n{}", saturation_table(&saturations));
+        }
+
+        export_results(&connection, sweep_started_at, &results, &saturations);
 
         println!("Analyser finalizing...\n");
     });
-}
\ No newline at end of file
+}
+
+fn export_results(
+    connection: &ConnectionConfig, started_at: DateTime<Utc>, results: &[RunResult], saturations: &[SaturationResult]
+) {
+    let format = CLI_ARGS.export_format;
+    if format == ExportFormat::None { return; }
+
+    let report = Report::new(RunMetadata {
+        tool_version: env!("CARGO_PKG_VERSION").to_string(),
+        host_uri: connection.display_uri(),
+        tls_host_uri: connection.display_tls_uri(),
+        plan: CLI_ARGS.plan.clone(),
+        started_at: started_at,
+        finished_at: Utc::now(),
+        measuring_runtime_s: CLI_ARGS.mrt,
+        reset_buffer_s: CLI_ARGS.reset_buffer,
+        delay_level_min: CLI_ARGS.delay_level_min,
+        delay_level_max: CLI_ARGS.delay_level_max,
+        instancecount_min: CLI_ARGS.instancecount_min,
+        instancecount_max: CLI_ARGS.instancecount_max,
+    }, results, saturations);
+    let file_stem = format!("{}-A", started_at.format("%Y%m%d_%H%M%S"));
+
+    let mut exports = Vec::new();
+    if format.csv() {
+        exports.push(write_export(&CLI_ARGS.export_dir, &format!("{}.csv", file_stem), &report.to_csv()));
+    }
+    if format.json() {
+        exports.push(report.to_json().and_then(|json| {
+            write_export(&CLI_ARGS.export_dir, &format!("{}.json", file_stem), &json)
+        }));
+    }
+
+    for export in exports {
+        match export {
+            Ok(path) => { write_log!("Results exported. [path={}]", path); }
+            Err(msg) => { write_log!("Results export failed. [reason={}]", msg); }
+        }
+    }
+}
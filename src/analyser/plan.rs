@@ -0,0 +1,218 @@
+use std::fs;
+use serde::Deserialize;
+use mqtt_playground::connection::{MqttVersion, SessionMode};
+use mqtt_playground::profile::LoadProfile;
+use mqtt_playground::subscriber::SubscriptionMode;
+use crate::cli_args::CliArgs;
+use crate::scenario::{Axes, Scenario};
+
+// Sweep plan read from a TOML file, the cartesian product of [product] followed by every
+// [[scenario]] as listed. Dimensions and fields left out fall back to the CLI flags
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Plan {
+    pub product: Option<Dimensions>,
+    #[serde(default, rename = "scenario")]
+    pub scenarios: Vec<PlanScenario>,
+}
+
+// Delays in milliseconds, rates in messages per second per publisher client. Delays,
+// rates and profiles together make up the pacing dimension
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Dimensions {
+    pub delays: Option<Vec<u64>>,
+    pub rates: Option<Vec<f64>>,
+    pub profiles: Option<Vec<String>>,
+    pub qos: Option<Vec<i32>>,
+    pub instancecounts: Option<Vec<usize>>,
+    pub tls: Option<Vec<bool>>,
+    pub mqtt_versions: Option<Vec<MqttVersion>>,
+    pub topologies: Option<Vec<String>>,
+    pub payload_sizes: Option<Vec<usize>>,
+    pub retain: Option<Vec<bool>>,
+    pub sessions: Option<Vec<SessionMode>>,
+}
+
+// One explicit iteration, at most one of delay, rate and profile
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PlanScenario {
+    pub delay: Option<u64>,
+    pub rate: Option<f64>,
+    pub profile: Option<String>,
+    pub qos: Option<i32>,
+    pub instancecount: Option<usize>,
+    pub tls: Option<bool>,
+    pub mqtt_version: Option<MqttVersion>,
+    pub topology: Option<String>,
+    pub payload_size: Option<usize>,
+    pub retain: Option<bool>,
+    pub session: Option<SessionMode>,
+}
+
+impl Plan {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let content = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let plan: Plan = toml::from_str(&content).map_err(|e| format!("{}: {}", path, e))?;
+        if plan.product.is_none() && plan.scenarios.is_empty() {
+            return Err(format!("{}: neither [product] nor [[scenario]] given", path))
+        }
+        return Ok(plan)
+    }
+
+    // Expands the plan into the full list of iterations, in sweep order
+    pub fn scenarios(&self, args: &CliArgs, default_tls: bool) -> Result<Vec<Scenario>, String> {
+        let defaults = Axes::from_cli_args(args, default_tls);
+        let mut scenarios = Vec::new();
+
+        if let Some(product) = self.product.as_ref() {
+            let mut pacings = Vec::new();
+            pacings.extend(product.delays.iter().flatten().map(|&x| (x, None)));
+            for &rate in product.rates.iter().flatten() {
+                pacings.push((0, Some(parse_rate(rate)?)));
+            }
+            for profile in product.profiles.iter().flatten() {
+                pacings.push((0, Some(profile.parse::<LoadProfile>()?)));
+            }
+
+            let topologies = product.topologies.as_ref()
+                .map(|x| x.iter().map(|x| x.parse::<SubscriptionMode>()).collect::<Result<Vec<SubscriptionMode>, String>>())
+                .transpose()?;
+            let axes = Axes {
+                pacings: if pacings.is_empty() { defaults.pacings.clone() } else { pacings },
+                qos: pick(&product.qos, &defaults.qos, "qos")?,
+                instancecounts: pick(&product.instancecounts, &defaults.instancecounts, "instancecounts")?,
+                transports: pick(&product.tls, &defaults.transports, "tls")?,
+                versions: pick(&product.mqtt_versions, &defaults.versions, "mqtt_versions")?,
+                topologies: pick(&topologies, &defaults.topologies, "topologies")?,
+                payload_sizes: pick(&product.payload_sizes, &defaults.payload_sizes, "payload_sizes")?,
+                retain: pick(&product.retain, &defaults.retain, "retain")?,
+                sessions: pick(&product.sessions, &defaults.sessions, "sessions")?,
+            };
+            scenarios.extend(axes.expand());
+        }
+
+        for (i, scenario) in self.scenarios.iter().enumerate() {
+            scenarios.push(scenario.resolve(&defaults).map_err(|e| format!("scenario {}: {}", i + 1, e))?);
+        }
+
+        for scenario in scenarios.iter() {
+            if !(0..3).contains(&scenario.qos) {
+                return Err(format!("invalid qos {}", scenario.qos))
+            }
+            if scenario.instancecount == 0 {
+                return Err("instance count must be at least 1".to_string())
+            }
+        }
+        return Ok(scenarios)
+    }
+}
+
+impl PlanScenario {
+    // Fields left out take the first value of the matching CLI dimension
+    fn resolve(&self, defaults: &Axes) -> Result<Scenario, String> {
+        let (delay, profile) = match (self.delay, self.rate, self.profile.as_ref()) {
+            (None, None, None) => first(&defaults.pacings, "pacing")?,
+            (Some(delay), None, None) => (delay, None),
+            (None, Some(rate), None) => (0, Some(parse_rate(rate)?)),
+            (None, None, Some(profile)) => (0, Some(profile.parse::<LoadProfile>()?)),
+            _ => { return Err("only one of delay, rate and profile can be given".to_string()) }
+        };
+        return Ok(Scenario {
+            delay,
+            qos: match self.qos { Some(qos) => qos, None => first(&defaults.qos, "qos")? },
+            instancecount: match self.instancecount { Some(n) => n, None => first(&defaults.instancecounts, "instancecount")? },
+            tls: match self.tls { Some(tls) => tls, None => first(&defaults.transports, "tls")? },
+            mqtt_version: match self.mqtt_version { Some(version) => version, None => first(&defaults.versions, "mqtt_version")? },
+            topology: match self.topology.as_ref() {
+                Some(topology) => topology.parse::<SubscriptionMode>()?,
+                None => first(&defaults.topologies, "topology")?,
+            },
+            payload_size: match self.payload_size { Some(size) => size, None => first(&defaults.payload_sizes, "payload_size")? },
+            retain: match self.retain { Some(retain) => retain, None => first(&defaults.retain, "retain")? },
+            session: match self.session { Some(session) => session, None => first(&defaults.sessions, "session")? },
+            profile,
+        })
+    }
+}
+
+fn parse_rate(rate: f64) -> Result<LoadProfile, String> {
+    let profile = LoadProfile::Constant { rate };
+    profile.validate()?;
+    return Ok(profile)
+}
+
+// The plan's values of a dimension if listed, the CLI's otherwise
+fn pick<T: Clone>(planned: &Option<Vec<T>>, default: &[T], name: &str) -> Result<Vec<T>, String> {
+    return match planned {
+        Some(values) if values.is_empty() => Err(format!("dimension {} is empty", name)),
+        Some(values) => Ok(values.clone()),
+        None => Ok(default.to_vec()),
+    }
+}
+
+fn first<T: Clone>(values: &[T], name: &str) -> Result<T, String> {
+    return values.first().cloned().ok_or(format!("no {} given, neither in the plan nor on the command line", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn plan(content: &str) -> Result<Vec<Scenario>, String> {
+        let args = CliArgs::parse_from(["analyser", "--IMIN=2", "--IMAX=2"]);
+        let plan: Plan = toml::from_str(content).map_err(|e| e.to_string())?;
+        return plan.scenarios(&args, false)
+    }
+
+    #[test]
+    fn product_expands_with_cli_defaults() {
+        let scenarios = plan("[product]\nrates = [100.0, 200.0]\nqos = [0, 2]\ntopologies = [\"fanin\"]\n").unwrap();
+        assert_eq!(scenarios.len(), 4);
+        assert!(scenarios.iter().all(|x| x.instancecount == 2 && x.topology == SubscriptionMode::FanIn && x.delay == 0));
+        assert_eq!(scenarios[0].profile, Some(LoadProfile::Constant { rate: 100.0 }));
+        assert_eq!(scenarios[1].qos, 2);
+    }
+
+    #[test]
+    fn explicit_scenarios_follow_the_product() {
+        let scenarios = plan(
+            "[product]\ndelays = [4]\nqos = [1]\n\n[[scenario]]\nprofile = \"ramp:100:5000:30\"\nqos = 2\n\n[[scenario]]\ndelay = 8\n"
+        ).unwrap();
+        assert_eq!(scenarios.len(), 3);
+        assert_eq!((scenarios[0].delay, scenarios[0].qos), (4, 1));
+        assert_eq!(scenarios[1].profile, Some(LoadProfile::Ramp { from: 100.0, to: 5000.0, duration_s: 30.0 }));
+        assert_eq!(scenarios[1].qos, 2);
+        // left out fields take the first value of the CLI dimension
+        assert_eq!((scenarios[2].delay, scenarios[2].qos, scenarios[2].instancecount), (8, 0, 2));
+    }
+
+    #[test]
+    fn invalid_plans_are_rejected() {
+        for content in [
+            "[product]\nqos = []\n",
+            "[product]\nqos = [3]\n",
+            "[product]\nunknown = [1]\n",
+            "[product]\ntopologies = [\"ring\"]\n",
+            "[product]\nprofiles = [\"constant:0\"]\n",
+            "[[scenario]]\ndelay = 1\nrate = 10.0\n",
+            "[[scenario]]\ninstancecount = 0\n",
+            "[[scenario]]\nrate = -1.0\n",
+        ] {
+            assert!(plan(content).is_err(), "{}", content);
+        }
+    }
+
+    #[test]
+    fn empty_plan_files_are_rejected() {
+        let path = std::env::temp_dir().join(format!("mqtt_playground-plan-{}.toml", std::process::id()));
+        fs::write(&path, "# nothing planned\n").unwrap();
+        assert!(Plan::load(&path.display().to_string()).is_err());
+        fs::write(&path, "[[scenario]]\ndelay = 1\n").unwrap();
+        assert_eq!(Plan::load(&path.display().to_string()).unwrap().scenarios.len(), 1);
+        let _ = fs::remove_file(&path);
+        assert!(Plan::load("/nonexistent/plan.toml").is_err());
+    }
+}
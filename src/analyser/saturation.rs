@@ -0,0 +1,147 @@
+use mqtt_playground::log;
+use mqtt_playground::write_log;
+use mqtt_playground::profile::LoadProfile;
+use mqtt_playground::stats::{RunResult, SaturationResult};
+use crate::cli_args::{CliArgs, CLI_ARGS};
+use crate::scenario::Scenario;
+use crate::session::{IterationError, Session};
+
+// Checks the search parameters up front, a factor of 1 or less never leaves the ramp-up phase and
+// a precision of 0 or less never ends the bisection
+pub fn validate(args: &CliArgs) -> Result<(), String> {
+    let finite_above = |x: f64, min: f64| x.is_finite() && x > min;
+    if !finite_above(args.sat_start_rate, 0.0) || !finite_above(args.sat_max_rate, 0.0) {
+        return Err(format!("--SAT_START_RATE {} and --SAT_MAX_RATE {} have to be above 0", args.sat_start_rate, args.sat_max_rate))
+    }
+    if !finite_above(args.sat_factor, 1.0) {
+        return Err(format!("--SAT_FACTOR {} has to be above 1", args.sat_factor))
+    }
+    if !finite_above(args.sat_precision, 0.0) {
+        return Err(format!("--SAT_PRECISION {} has to be above 0", args.sat_precision))
+    }
+    return Ok(())
+}
+
+// Finds the highest constant rate per publisher client `base` sustains: the rate is multiplied by
+// --SAT_FACTOR until a probe fails, then the knee is binary searched between the last passing
+// and the first failing rate. Every probe is appended to `results`, errors only if the sweep has to stop.
+// A resumed search replays the probes the checkpoint holds and continues from there
+pub async fn search(session: &mut Session, base: &Scenario, results: &mut Vec<RunResult>) -> Result<SaturationResult, String> {
+    let mut passing: Option<f64> = None;
+    let mut failing: Option<(f64, String)> = None;
+    let mut n_probes = 0;
+
+    let mut rate = CLI_ARGS.sat_start_rate.min(CLI_ARGS.sat_max_rate);
+    while failing.is_none() {
+        n_probes += 1;
+        match probe(session, base, rate, results).await? {
+            Ok(()) => { passing = Some(rate); }
+            Err(reason) => { failing = Some((rate, reason)); }
+        }
+        if rate >= CLI_ARGS.sat_max_rate { break; }
+        rate = (rate * CLI_ARGS.sat_factor).min(CLI_ARGS.sat_max_rate);
+    }
+
+    while let (Some(low), Some((high, _))) = (passing, failing.as_ref()) {
+        if high - low <= high * CLI_ARGS.sat_precision { break; }
+        let rate = (low + high) / 2.0;
+        n_probes += 1;
+        match probe(session, base, rate, results).await? {
+            Ok(()) => { passing = Some(rate); }
+            Err(reason) => { failing = Some((rate, reason)); }
+        }
+    }
+
+    let saturation = SaturationResult {
+        qos: base.qos,
+        instancecount: base.instancecount,
+        tls: base.tls,
+        retain: base.retain,
+        session: base.session,
+        mqtt_version: base.mqtt_version,
+        topology: base.topology.to_string(),
+        payload_size: base.payload_size,
+        knee_rate: passing,
+        knee_throughput: passing.map(|x| x * base.instancecount as f64),
+        failing_rate: failing.as_ref().map(|x| x.0),
+        limit: failing.map(|x| x.1),
+        n_probes,
+    };
+    write_log!(r#"Saturation search finished. [
+    qos={},
+    instancecount={},
+    knee_rate={},
+    knee_throughput={},
+    failing_rate={},
+    limit={},
+    n_probes={},
+]
+    "#, saturation.qos, saturation.instancecount,
+    saturation.knee_rate.map(|x| format!("{:.0}", x)).unwrap_or("NA".to_string()),
+    saturation.knee_throughput.map(|x| format!("{:.0}", x)).unwrap_or("NA".to_string()),
+    saturation.failing_rate.map(|x| format!("{:.0}", x)).unwrap_or("NA".to_string()),
+    saturation.limit.clone().unwrap_or("max rate reached".to_string()), n_probes);
+    return Ok(saturation)
+}
+
+// Runs `base` at a constant `rate`, the inner result tells whether the probe passed and why not
+async fn probe(session: &mut Session, base: &Scenario, rate: f64, results: &mut Vec<RunResult>) -> Result<Result<(), String>, String> {
+    let scenario = Scenario { profile: Some(LoadProfile::Constant { rate }), ..base.clone() };
+    let verdict = match session.run_scenario(&scenario).await {
+        Ok(result) => {
+            let verdict = check(&result);
+            results.push(result);
+            verdict
+        }
+        // a failed iteration at a given rate is as good a limit as any
+        Err(IterationError::Skipped(msg)) => Err(format!("iteration failed: {}", msg)),
+        Err(IterationError::SweepAborted(msg)) => { return Err(msg) }
+    };
+    write_log!("Saturation probe. [qos={}] [instancecount={}] [rate={:.0}] [passed={}] [reason={}]\n",
+        base.qos, base.instancecount, rate, verdict.is_ok(), verdict.as_ref().err().cloned().unwrap_or_default());
+    return Ok(verdict)
+}
+
+// Compares a probe against the --SAT_MAX_LOSS, --SAT_MAX_P99_MS and --SAT_MIN_ACHIEVED thresholds
+fn check(result: &RunResult) -> Result<(), String> {
+    let n_received = result.n_delivered();
+    if n_received == 0 {
+        return Err("no message received".to_string())
+    }
+
+    let loss = result.n_lost() as f64 / (n_received + result.n_lost()) as f64;
+    if loss > CLI_ARGS.sat_max_loss {
+        return Err(format!("loss {:.4} above {}", loss, CLI_ARGS.sat_max_loss))
+    }
+
+    let p99_ms = result.latency().summary().p99 as f64 / 1000.0;
+    if p99_ms > CLI_ARGS.sat_max_p99_ms {
+        return Err(format!("p99 latency {:.1}ms above {}ms", p99_ms, CLI_ARGS.sat_max_p99_ms))
+    }
+
+    // publishers falling behind their pacer means the client side saturated before the broker did
+    if let Some(target_rate) = result.target_rate().filter(|&x| x > 0.0) {
+        let achieved = result.achieved_rate() / target_rate;
+        if achieved < CLI_ARGS.sat_min_achieved {
+            return Err(format!("achieved {:.2} of the target rate, below {}", achieved, CLI_ARGS.sat_min_achieved))
+        }
+    }
+    return Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn search_parameters_that_never_converge_are_rejected() {
+        assert!(validate(&CliArgs::parse_from(["analyser"])).is_ok());
+        for arg in [
+            "--SAT_FACTOR=1", "--SAT_FACTOR=0.5", "--SAT_FACTOR=inf", "--SAT_PRECISION=0", "--SAT_PRECISION=-0.1",
+            "--SAT_START_RATE=0", "--SAT_MAX_RATE=-1", "--SAT_START_RATE=NaN",
+        ] {
+            assert!(validate(&CliArgs::parse_from(["analyser", arg])).is_err(), "{}", arg);
+        }
+    }
+}
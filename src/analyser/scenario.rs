@@ -0,0 +1,134 @@
+use std::fmt;
+use crate::cli_args::CliArgs;
+use mqtt_playground::connection::{MqttVersion, SessionMode};
+use mqtt_playground::profile::LoadProfile;
+use mqtt_playground::subscriber::SubscriptionMode;
+
+// One sweep iteration
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scenario {
+    pub delay: u64,
+    pub qos: i32,
+    pub instancecount: usize,
+    pub tls: bool,
+    pub mqtt_version: MqttVersion,
+    // how the subscribers map onto the publisher topics
+    pub topology: SubscriptionMode,
+    pub payload_size: usize,
+    pub retain: bool,
+    pub session: SessionMode,
+    // rate over time of every publisher client, replaces the delay when set
+    pub profile: Option<LoadProfile>,
+}
+
+// Identifies the iteration in logs and checkpoints
+impl fmt::Display for Scenario {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(
+            f, "delay={} qos={} instancecount={} tls={} mqtt_version={} topology={} payload_size={} retain={} session={} profile={}",
+            self.delay, self.qos, self.instancecount, self.tls, self.mqtt_version, self.topology, self.payload_size, self.retain, self.session,
+            self.profile.as_ref().map(|x| x.to_string()).unwrap_or("none".to_string())
+        )
+    }
+}
+
+// Values of every sweep dimension, expanded as a cartesian product
+#[derive(Debug, Clone)]
+pub struct Axes {
+    // either a delay or a load profile, the delay stays 0 with a profile
+    pub pacings: Vec<(u64, Option<LoadProfile>)>,
+    pub qos: Vec<i32>,
+    pub instancecounts: Vec<usize>,
+    pub transports: Vec<bool>,
+    pub versions: Vec<MqttVersion>,
+    pub topologies: Vec<SubscriptionMode>,
+    pub payload_sizes: Vec<usize>,
+    pub retain: Vec<bool>,
+    pub sessions: Vec<SessionMode>,
+}
+
+impl Axes {
+    pub fn from_cli_args(args: &CliArgs, default_tls: bool) -> Self {
+        // either the delay levels or the load profiles
+        let pacings = if !args.profiles.is_empty() {
+            args.profiles.iter().map(|x| (0, Some(x.clone()))).collect::<Vec<(u64, Option<LoadProfile>)>>()
+        } else if !args.rates.is_empty() {
+            args.rates.iter().map(|&rate| (0, Some(LoadProfile::Constant { rate }))).collect()
+        } else {
+            (args.delay_level_min..(args.delay_level_max+1)).map(|delay_exp| {
+                let delay = if delay_exp == 0 { 0 }
+                    else { (2 as u64).pow((delay_exp - 1) as u32) };
+                (delay, None)
+            }).collect()
+        };
+
+        let topologies = if let Some(group) = args.shared_group.as_ref() {
+            vec![SubscriptionMode::Shared { group: group.clone() }]
+        } else if !args.fan_outs.is_empty() {
+            args.fan_outs.iter().map(|&fan_out| SubscriptionMode::FanOut { fan_out, wildcard: args.wildcard }).collect()
+        } else if args.fan_in {
+            vec![SubscriptionMode::FanIn]
+        } else {
+            vec![SubscriptionMode::Paired]
+        };
+
+        return Self {
+            pacings,
+            qos: vec![0, 1, 2],
+            instancecounts: (args.instancecount_min..(args.instancecount_max+1)).collect(),
+            transports: if args.tls_sweep { vec![false, true] } else { vec![default_tls] },
+            versions: if args.version_sweep { vec![MqttVersion::V311, MqttVersion::V5] } else { vec![args.mqtt_version] },
+            topologies,
+            payload_sizes: args.payload_sizes.clone(),
+            retain: vec![args.retain],
+            sessions: args.sessions.clone(),
+        }
+    }
+
+    // Every combination, in sweep order
+    pub fn expand(&self) -> Vec<Scenario> {
+        let mut scenarios = Vec::new();
+        for (delay, profile) in self.pacings.iter() {
+            let delay = *delay;
+            for &qos in self.qos.iter() {
+                for &instancecount in self.instancecounts.iter() {
+                    for &tls in self.transports.iter() {
+                        for &mqtt_version in self.versions.iter() {
+                            for topology in self.topologies.iter() {
+                                for &payload_size in self.payload_sizes.iter() {
+                                    for &retain in self.retain.iter() {
+                                        for &session in self.sessions.iter() {
+                                            scenarios.push(Scenario {
+                                                delay, qos, instancecount, tls, mqtt_version,
+                                                topology: topology.clone(), payload_size, retain, session,
+                                                profile: profile.clone(),
+                                            });
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        return scenarios
+    }
+}
+
+// Expands the CLI ranges into the full list of iterations, in sweep order
+pub fn from_cli_args(args: &CliArgs, default_tls: bool) -> Vec<Scenario> {
+    return Axes::from_cli_args(args, default_tls).expand()
+}
+
+// One base iteration per saturation search, the search sets the rate of every probe
+pub fn saturation_bases(scenarios: Vec<Scenario>) -> Vec<Scenario> {
+    let mut bases: Vec<Scenario> = Vec::new();
+    for scenario in scenarios {
+        let base = Scenario { delay: 0, profile: None, ..scenario };
+        if !bases.contains(&base) {
+            bases.push(base);
+        }
+    }
+    return bases
+}
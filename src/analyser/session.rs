@@ -0,0 +1,361 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::sleep;
+use tokio::{spawn, sync::Mutex};
+use paho_mqtt::{AsyncClient, AsyncReceiver, Message};
+use chrono::Utc;
+
+use mqtt_playground::log;
+use mqtt_playground::write_log;
+use mqtt_playground::subscriber::{Subscriber, SubscriptionMode, reconcile, sent_per_topic};
+use mqtt_playground::connection::ConnectionConfig;
+use mqtt_playground::stats::{FaultReport, PublisherStats, RunResult};
+use mqtt_playground::fault::{FaultEvent, FaultTarget, spawn_broker_faults};
+use mqtt_playground::broker::{BrokerMetrics, format_metric};
+use mqtt_playground::command::{
+    Command, CommandMessage, ControllerMonitor, RunConfig, Status, partition
+};
+use crate::checkpoint::Checkpoint;
+use crate::cli_args::CLI_ARGS;
+use crate::scenario::Scenario;
+
+// Why an iteration produced no result
+#[derive(Debug, Clone, PartialEq)]
+pub enum IterationError {
+    // the iteration failed, the sweep can go on with the next one
+    Skipped(String),
+    // no controller left to run anything on
+    SweepAborted(String),
+}
+
+// Everything the analyser needs to run iterations against the controllers
+pub struct Session {
+    pub connection: ConnectionConfig,
+    pub analyser_client: AsyncClient,
+    pub resp_stream: AsyncReceiver<Option<Message>>,
+    pub monitor: ControllerMonitor,
+    pub broker_metrics: Option<Arc<Mutex<BrokerMetrics>>>,
+    pub checkpoint: Checkpoint,
+    command_id: u64,
+}
+
+impl Session {
+    pub fn new(
+        connection: ConnectionConfig, analyser_client: AsyncClient, resp_stream: AsyncReceiver<Option<Message>>,
+        monitor: ControllerMonitor, broker_metrics: Option<Arc<Mutex<BrokerMetrics>>>, checkpoint: Checkpoint
+    ) -> Self {
+        return Self { connection, analyser_client, resp_stream, monitor, broker_metrics, checkpoint, command_id: 0 }
+    }
+
+    // Runs one iteration, or hands back its result if an earlier run of the sweep completed it
+    pub async fn run_scenario(&mut self, scenario: &Scenario) -> Result<RunResult, IterationError> {
+        if let Some(result) = self.checkpoint.take(scenario) {
+            write_log!("Skipping completed iteration. [{}]\n", scenario);
+            return Ok(result)
+        }
+
+        let result = self.run_iteration(scenario).await?;
+        if let Err(msg) = self.checkpoint.record(scenario, &result) {
+            write_log!("Checkpoint not saved, the iteration will rerun on resume. [reason={}]\n", msg);
+        }
+        return Ok(result)
+    }
+
+    // Runs one iteration on the live controllers, from connecting the subscribers to collecting their stats
+    async fn run_iteration(&mut self, scenario: &Scenario) -> Result<RunResult, IterationError> {
+        let Scenario { delay, qos, instancecount, tls, mqtt_version, ref topology, payload_size, retain, session, ref profile } = *scenario;
+        let session_config = CLI_ARGS.session_config(session);
+        let payload_kind = CLI_ARGS.payload_kind;
+
+        println!(r#"Parameters updated. [
+    qos={},
+    delay={},
+    instancecount={},
+    tls={},
+    mqtt_version={},
+    topology={},
+    payload={}x{}B,
+    retain={},
+    session={},
+    profile={},
+]
+        "#, qos, delay, instancecount, tls, mqtt_version, topology, payload_kind, payload_size, retain, session,
+        profile.as_ref().map(|x| x.to_string()).unwrap_or("none".to_string()));
+
+        let run_connection = match if tls { self.connection.to_tls() } else { Ok(self.connection.clone()) } {
+            Ok(run_connection) => run_connection.with_version(mqtt_version).with_session(session_config),
+            Err(msg) => {
+                write_log!("Skipping TLS iteration. [reason={}]\n", msg);
+                return Err(IterationError::Skipped(msg))
+            }
+        };
+
+        if self.monitor.alive_controllers().is_empty() {
+            write_log!("No controller alive, pausing the sweep.\n");
+            if let Err(msg) = self.monitor.await_alive(&self.resp_stream, 1, Duration::from_secs(CLI_ARGS.pause_timeout)).await {
+                write_log!("Controller did not come back, aborting the sweep. [reason={}]\n", msg);
+                return Err(IterationError::SweepAborted(msg))
+            }
+            write_log!("Controller back online, resuming the sweep.\n");
+        }
+        let assignments = partition(&self.monitor.alive_controllers(), instancecount);
+        let participants = assignments.iter().map(|x| x.0.clone()).collect::<Vec<String>>();
+
+        let n_subscribers = match topology {
+            SubscriptionMode::Paired => instancecount,
+            SubscriptionMode::Shared { .. } => CLI_ARGS.shared_members.unwrap_or(instancecount),
+            SubscriptionMode::FanOut { fan_out, wildcard: true } => *fan_out,
+            SubscriptionMode::FanOut { fan_out, wildcard: false } => fan_out * instancecount,
+            SubscriptionMode::FanIn => 1,
+        };
+
+        let started_at = Utc::now();
+        let mut subscriber_handle = None;
+        let mut graceful_stop = None;
+        let mut ledger = None;
+        let mut subscriber_faults = None;
+        match Subscriber::connect(
+            &run_connection,
+            n_subscribers, delay, qos, topology.clone(), CLI_ARGS.timeline_ms, CLI_ARGS.reorder_window,
+            CLI_ARGS.fault_specs(FaultTarget::Subscribers)
+        ).await {
+            Ok(mut subscriber) => {
+                graceful_stop = Some(Arc::clone(&subscriber.make_graceful_stop));
+                ledger = Some(Arc::clone(&subscriber.ledger));
+                subscriber_faults = Some(Arc::clone(&subscriber.faults));
+
+                write_log!("Starting new subscriber clients...\n");
+
+                subscriber_handle = Some(spawn(async move {
+                    let stats = subscriber.start().await;
+                    let total_n_messages = stats.iter().map(|x| {x.n_messages}).sum::<u64>();
+                    write_log!(r#"Subscriber clients 0..{} terminated. [
+    total number of messages received={}
+]
+                    "#, n_subscribers, total_n_messages);
+                    stats
+                }));
+            }
+            Err(msg) => {
+                write_log!("Subscriber clients failed to connect. [reason={}]\n", msg);
+            }
+        }
+
+        let ready = self.send_commands(
+            assignments.iter().map(|(controller_id, first_index, count)| (
+                controller_id.clone(),
+                Command::Start(RunConfig { qos, delay, instancecount: *count, first_index: *first_index, tls, mqtt_version,
+                    payload_size, payload_kind, profile: profile.clone(), retain, session: session_config,
+                    faults: CLI_ARGS.fault_specs(FaultTarget::Publishers)
+                })
+            )).collect()
+        ).await;
+        let not_ready = ready.iter()
+            .filter(|x| !matches!(x.1, Ok(Status::Ready { .. })))
+            .map(|x| format!("{}: {}", x.0, describe_failure(&x.1)))
+            .collect::<Vec<String>>();
+        let mut failure = None;
+        let broker_faults = Arc::new(Mutex::new(Vec::new()));
+        let mut broker_fault_tasks = Vec::new();
+        if !not_ready.is_empty() {
+            write_log!(r#"Publishers not ready, skipping iteration. [
+    qos={},
+    delay={},
+    instancecount={},
+    reason={:?},
+]
+            "#, qos, delay, instancecount, not_ready);
+            failure = Some(format!("publishers not ready: {:?}", not_ready));
+        } else {
+            if let Some(metrics) = self.broker_metrics.as_ref() {
+                metrics.lock().await.begin_window();
+            }
+            // timed from the start of the measuring window, the iteration's clients only come back with --RECONNECT
+            if let Some(restart_command) = CLI_ARGS.broker_restart_cmd.as_ref() {
+                broker_fault_tasks = spawn_broker_faults(&CLI_ARGS.faults, restart_command, &broker_faults);
+            }
+
+            if let Err(msg) = self.monitor.watch(&self.resp_stream, &participants, Duration::from_secs(CLI_ARGS.mrt)).await {
+                write_log!(r#"Controller lost, aborting iteration. [
+    qos={},
+    delay={},
+    instancecount={},
+    reason={},
+]
+                "#, qos, delay, instancecount, msg);
+                failure = Some(format!("controller lost: {}", msg));
+            }
+        }
+
+        for task in broker_fault_tasks {
+            task.abort();
+        }
+
+        let broker = if let Some(metrics) = self.broker_metrics.as_ref() {
+            metrics.lock().await.end_window()
+        } else { None };
+
+        // controllers report per client statistics, concatenated in partition order they are indexed globally
+        let mut publishers: Option<Vec<PublisherStats>> = Some(Vec::new());
+        let mut faults: Vec<FaultEvent> = broker_faults.lock().await.clone();
+        for (_, stopped) in self.send_commands(
+            participants.iter().map(|x| (x.clone(), Command::Stop)).collect()
+        ).await {
+            match (stopped, publishers.as_mut()) {
+                (Ok(Status::Stopped { publishers: stats, faults: publisher_faults }), Some(all)) => {
+                    all.extend(stats);
+                    faults.extend(publisher_faults);
+                }
+                _ => { publishers = None; }
+            }
+        }
+        let publishers = publishers.unwrap_or_default();
+
+        if let Some(stop) = graceful_stop {
+            write_log!("Terminating subscriber clients...\n");
+            *stop.lock().await = true;
+        }
+
+        sleep(Duration::from_secs(CLI_ARGS.reset_buffer)).await;
+
+        let mut clients = match subscriber_handle {
+            Some(handle) => handle.await.map_err(|e| IterationError::Skipped(e.to_string()))?,
+            None => { return Err(IterationError::Skipped("subscriber clients failed to connect".to_string())) }
+        };
+        if let Some(msg) = failure {
+            return Err(IterationError::Skipped(msg))
+        }
+        if let Some(subscriber_faults) = subscriber_faults {
+            faults.extend(subscriber_faults.lock().await.iter().cloned());
+        }
+        faults.sort_by_key(|x| x.at);
+        let faults = faults.into_iter()
+            .map(|x| FaultReport::new(x, CLI_ARGS.fault_margin_s * 1000, &clients))
+            .collect::<Vec<FaultReport>>();
+
+        // an empty publisher list means a controller didn't report, the sequence gaps are all there is then
+        reconcile(&mut clients, topology, &publishers);
+        let shared = match (topology, ledger) {
+            (SubscriptionMode::Shared { group }, Some(ledger)) => Some(ledger.lock().await.summary(
+                group, &clients, &sent_per_topic(&publishers, qos, delay)
+            )),
+            _ => None,
+        };
+        let result = RunResult {
+            qos, delay, instancecount, tls, retain, session, mqtt_version,
+            fan_out: topology.fan_out(), topology: topology.to_string(),
+            payload_size, payload_kind, profile: profile.clone(),
+            started_at, ended_at: Utc::now(),
+            clients, publishers, broker, shared, faults,
+        };
+        let n_messages_sent = result.n_messages_sent()
+            .map(|x| x.to_string())
+            .unwrap_or("NA".to_string());
+        write_log!(r#"Iteration finished. [
+    qos={},
+    delay={},
+    instancecount={},
+    n_subscribers={},
+    total number of messages sent={},
+    total number of messages received={},
+    n_lost={},
+    n_duplicates={},
+    n_reordered={},
+    n_late={},
+    reorder_distance=[{}],
+    latency_us=[{}],
+    subscriber_reconnects={},
+    publisher_reconnects={},
+    n_recovered={},
+    broker_messages_received={},
+    broker_messages_sent={},
+]
+        "#, qos, delay, instancecount, n_subscribers, n_messages_sent, result.n_messages(),
+        result.n_lost(), result.n_duplicates(), result.n_reordered(), result.n_late(), result.reorder_distance().summary(),
+        result.latency().summary(),
+        result.subscriber_connection().n_reconnects, result.publisher_connection().n_reconnects,
+        result.subscriber_connection().n_recovered + result.publisher_connection().n_recovered,
+        format_metric(broker.and_then(|x| x.messages_received())),
+        format_metric(broker.and_then(|x| x.messages_sent())));
+        if let Some(shared) = result.shared.as_ref() {
+            write_log!(r#"Shared subscription delivery. [
+    group={},
+    n_publishers={},
+    n_unique={},
+    n_duplicates={},
+    n_lost={},
+    n_beyond_window={},
+    member_messages={:?},
+    imbalance={:.2},
+]
+            "#, shared.group, shared.n_publishers, shared.n_unique, shared.n_duplicates,
+            shared.n_lost, shared.n_beyond_window, shared.member_messages, shared.imbalance);
+        }
+        if let Some(profile) = result.profile.as_ref() {
+            let timeline = result.timeline();
+            let n_publishers = result.publishers.len().max(1) as f64;
+            for (i, bucket) in timeline.buckets.iter().enumerate() {
+                let offset_s = (i as u64 * timeline.bucket_ms) as f64 / 1000.0;
+                write_log!("Timeline {}s. [target_rate={:.0}] [messages={}] [lost={}] [latency_mean_us={}] [latency_max_us={}]",
+                    offset_s, profile.rate_at(offset_s) * n_publishers, bucket.n_messages, bucket.n_lost(),
+                    bucket.latency_mean_us(), bucket.latency_max_us);
+            }
+        }
+        for report in result.faults.iter() {
+            write_log!("Fault at {}. [target={}] [clients={:?}] [down_ms={}] [messages={}] [lost={}] [duplicates={}] [recovered={}] [errors={:?}]",
+                report.fault.at.to_rfc3339(), report.fault.target, report.fault.clients, report.fault.down_ms,
+                report.n_messages, report.n_lost, report.n_duplicates, report.n_recovered, report.fault.errors);
+        }
+        for publisher in result.publishers.iter() {
+            if let Some(target_rate) = publisher.target_rate {
+                write_log!("Publisher client {} rate. [target={:.1}] [achieved={:.1}]",
+                    publisher.client_index, target_rate, publisher.achieved_rate());
+            }
+            write_log!("Publisher client {} acknowledgements. [ack_latency_us=[{}]] [n_failed={}] [n_retries={}] [errors={:?}]",
+                publisher.client_index, publisher.ack_latency.summary(), publisher.n_failed, publisher.n_retries, publisher.errors);
+        }
+        return Ok(result)
+    }
+
+    // Shuts down every live controller
+    pub async fn kill_all(&mut self) {
+        let kill_all = self.monitor.alive_controllers().into_iter().map(|x| (x, Command::KillAll)).collect();
+        for (controller_id, killed) in self.send_commands(kill_all).await {
+            if let Err(msg) = killed {
+                write_log!("Controller did not acknowledge killall. [controller id={}] [reason={}]\n", controller_id, msg);
+            }
+        }
+    }
+
+    // Publishes one command per controller, then waits for each controller's final response
+    pub async fn send_commands(&mut self, commands: Vec<(String, Command)>) -> Vec<(String, Result<Status, String>)> {
+        let mut sent = Vec::new();
+        for (controller_id, command) in commands {
+            self.command_id += 1;
+            let published = self.analyser_client.publish(
+                CommandMessage::new(self.command_id, command).to_message(&controller_id)
+            ).await.map_err(|e| e.to_string());
+            sent.push((controller_id, self.command_id, published));
+        }
+
+        let mut responses = Vec::new();
+        for (controller_id, id, published) in sent {
+            let status = match published {
+                Ok(()) => self.monitor.await_response(
+                    &self.resp_stream, &controller_id, id, Duration::from_secs(CLI_ARGS.ready_timeout)
+                ).await,
+                Err(msg) => Err(msg),
+            };
+            responses.push((controller_id, status));
+        }
+        return responses
+    }
+}
+
+fn describe_failure(status: &Result<Status, String>) -> String {
+    return match status {
+        Ok(Status::Rejected { reason }) => reason.clone(),
+        Ok(other) => format!("unexpected response {:?}", other),
+        Err(msg) => msg.clone(),
+    }
+}
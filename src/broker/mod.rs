@@ -0,0 +1,163 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// Broker-side counters as published under $SYS, fields stay None until the broker reports them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct BrokerSample {
+    pub clients_connected: Option<f64>,
+    pub messages_received: Option<f64>,
+    pub messages_sent: Option<f64>,
+    pub bytes_received: Option<f64>,
+    pub bytes_sent: Option<f64>,
+    pub load_messages_received_1min: Option<f64>,
+    pub load_messages_sent_1min: Option<f64>,
+    pub heap_current: Option<f64>,
+}
+
+impl BrokerSample {
+    // Applies one $SYS metric, `metric` being the topic with the broker/node prefix stripped.
+    // Returns false for metrics that aren't tracked
+    pub fn apply(&mut self, metric: &str, value: f64) -> bool {
+        let field = match metric {
+            // mosquitto, hivemq
+            "clients/connected" | "clients/active" |
+            // emqx
+            "stats/connections/count" => &mut self.clients_connected,
+            "messages/received" | "metrics/messages/received" => &mut self.messages_received,
+            "messages/sent" | "metrics/messages/sent" => &mut self.messages_sent,
+            "bytes/received" | "metrics/bytes/received" => &mut self.bytes_received,
+            "bytes/sent" | "metrics/bytes/sent" => &mut self.bytes_sent,
+            "load/messages/received/1min" => &mut self.load_messages_received_1min,
+            "load/messages/sent/1min" => &mut self.load_messages_sent_1min,
+            "heap/current" | "heap/current size" => &mut self.heap_current,
+            _ => { return false; }
+        };
+        *field = Some(value);
+        return true
+    }
+
+    // Sums the counters of several broker nodes
+    fn accumulate(&mut self, other: &BrokerSample) {
+        fn add(total: &mut Option<f64>, value: Option<f64>) {
+            if let Some(value) = value {
+                *total = Some(total.unwrap_or(0.0) + value);
+            }
+        }
+        add(&mut self.clients_connected, other.clients_connected);
+        add(&mut self.messages_received, other.messages_received);
+        add(&mut self.messages_sent, other.messages_sent);
+        add(&mut self.bytes_received, other.bytes_received);
+        add(&mut self.bytes_sent, other.bytes_sent);
+        add(&mut self.load_messages_received_1min, other.load_messages_received_1min);
+        add(&mut self.load_messages_sent_1min, other.load_messages_sent_1min);
+        add(&mut self.heap_current, other.heap_current);
+    }
+}
+
+// Splits a $SYS topic into (node, metric).
+// "$SYS/broker/clients/connected" => ("broker", "clients/connected")
+// "$SYS/brokers/emqx@host/stats/connections/count" => ("emqx@host", "stats/connections/count")
+pub fn parse_sys_topic(topic: &str) -> Option<(&str, &str)> {
+    let rest = topic.strip_prefix("$SYS/")?;
+    if let Some(rest) = rest.strip_prefix("brokers/") {
+        return rest.split_once('/')
+    }
+    return rest.split_once('/')
+}
+
+// Parses payloads such as "42", "0.53" or "1234 seconds"
+pub fn parse_sys_value(payload: &str) -> Option<f64> {
+    return payload.split_whitespace().next()?.parse::<f64>().ok()
+}
+
+// Latest $SYS state of every broker node, plus the window currently being measured
+#[derive(Debug, Default)]
+pub struct BrokerMetrics {
+    nodes: HashMap<String, BrokerSample>,
+    n_updates: u64,
+    window_start: Option<(DateTime<Utc>, BrokerSample)>,
+    window_n_updates: u64,
+    window_peak_clients: Option<f64>,
+}
+
+impl BrokerMetrics {
+    pub fn update(&mut self, topic: &str, payload: &str) {
+        if let (Some((node, metric)), Some(value)) = (parse_sys_topic(topic), parse_sys_value(payload)) {
+            if self.nodes.entry(node.to_string()).or_default().apply(metric, value) {
+                self.n_updates += 1;
+                self.window_n_updates += 1;
+                if let Some(clients) = self.snapshot().clients_connected {
+                    self.window_peak_clients = Some(self.window_peak_clients.unwrap_or(0.0).max(clients));
+                }
+            }
+        }
+    }
+
+    pub fn snapshot(&self) -> BrokerSample {
+        let mut total = BrokerSample::default();
+        self.nodes.values().for_each(|x| total.accumulate(x));
+        return total
+    }
+
+    pub fn begin_window(&mut self) {
+        let snapshot = self.snapshot();
+        self.window_start = Some((Utc::now(), snapshot));
+        self.window_n_updates = 0;
+        self.window_peak_clients = snapshot.clients_connected;
+    }
+
+    pub fn end_window(&mut self) -> Option<BrokerWindow> {
+        let (started_at, start) = self.window_start.take()?;
+        return Some(BrokerWindow {
+            started_at,
+            ended_at: Utc::now(),
+            start,
+            end: self.snapshot(),
+            n_updates: self.window_n_updates,
+            peak_clients_connected: self.window_peak_clients,
+        })
+    }
+}
+
+// Broker-side view of one measuring window
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct BrokerWindow {
+    pub started_at: DateTime<Utc>,
+    pub ended_at: DateTime<Utc>,
+    pub start: BrokerSample,
+    pub end: BrokerSample,
+    // number of $SYS updates received within the window
+    pub n_updates: u64,
+    pub peak_clients_connected: Option<f64>,
+}
+
+impl BrokerWindow {
+    pub fn messages_received(&self) -> Option<f64> {
+        return delta(self.start.messages_received, self.end.messages_received)
+    }
+
+    pub fn messages_sent(&self) -> Option<f64> {
+        return delta(self.start.messages_sent, self.end.messages_sent)
+    }
+
+    pub fn bytes_received(&self) -> Option<f64> {
+        return delta(self.start.bytes_received, self.end.bytes_received)
+    }
+
+    pub fn bytes_sent(&self) -> Option<f64> {
+        return delta(self.start.bytes_sent, self.end.bytes_sent)
+    }
+}
+
+// Counters reset on broker restart, a negative delta is reported as missing
+fn delta(start: Option<f64>, end: Option<f64>) -> Option<f64> {
+    if let (Some(start), Some(end)) = (start, end) {
+        if end >= start { return Some(end - start) }
+    }
+    return None
+}
+
+pub fn format_metric(value: Option<f64>) -> String {
+    return value.map(|x| format!("{}", x)).unwrap_or("NA".to_string())
+}
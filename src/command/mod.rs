@@ -0,0 +1,378 @@
+use chrono::{DateTime, Utc};
+use crate::connection::{MqttVersion, SessionConfig};
+use crate::payload::PayloadKind;
+use crate::fault::{FaultEvent, FaultSpec};
+use crate::stats::PublisherStats;
+use crate::profile::LoadProfile;
+use paho_mqtt::{AsyncReceiver, Message, QOS_1, QOS_2};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::{timeout, Instant};
+
+// Bumped whenever the command/response layout changes incompatibly
+pub const PROTOCOL_VERSION: u32 = 11;
+
+// Every controller listens on its own command topic and answers on its own
+// status/heartbeat topics, e.g. "request/command/<controller id>"
+const COMMAND_TOPIC_PREFIX: &str = "request/command";
+const RESPONSE_TOPIC_PREFIX: &str = "response/status";
+const HEARTBEAT_TOPIC_PREFIX: &str = "response/heartbeat";
+
+pub const RESPONSE_TOPIC_FILTER: &str = "response/status/+";
+pub const HEARTBEAT_TOPIC_FILTER: &str = "response/heartbeat/+";
+
+// How often the analyser re-checks the controller liveness while waiting
+const LIVENESS_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+pub fn command_topic(controller_id: &str) -> String {
+    return format!("{}/{}", COMMAND_TOPIC_PREFIX, controller_id)
+}
+
+pub fn response_topic(controller_id: &str) -> String {
+    return format!("{}/{}", RESPONSE_TOPIC_PREFIX, controller_id)
+}
+
+pub fn heartbeat_topic(controller_id: &str) -> String {
+    return format!("{}/{}", HEARTBEAT_TOPIC_PREFIX, controller_id)
+}
+
+// Full configuration of one publisher run
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RunConfig {
+    pub qos: i32,
+    pub delay: u64,
+    // number of publisher clients this controller runs
+    pub instancecount: usize,
+    // global index of the first client, so topics stay unique across controllers
+    pub first_index: usize,
+    // connect the publishers over TLS (the controller's TLS target)
+    #[serde(default)]
+    pub tls: bool,
+    // protocol version the publishers connect with
+    #[serde(default)]
+    pub mqtt_version: MqttVersion,
+    // body following the sequence header, in bytes
+    #[serde(default)]
+    pub payload_size: usize,
+    #[serde(default)]
+    pub payload_kind: PayloadKind,
+    // rate over time of every client, replaces the delay between messages when set
+    #[serde(default)]
+    pub profile: Option<LoadProfile>,
+    // publish with the retain flag, the topics are cleared when the clients stop
+    #[serde(default)]
+    pub retain: bool,
+    // reconnect and session behaviour of the publisher clients
+    #[serde(default)]
+    pub session: SessionConfig,
+    // faults injected into the publisher clients, timed from the start of publishing
+    #[serde(default)]
+    pub faults: Vec<FaultSpec>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Command {
+    // (re)start the publishers with the given configuration
+    Start(RunConfig),
+    // stop the running publishers
+    Stop,
+    // stop the running publishers and shut the controller down
+    KillAll,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommandMessage {
+    pub version: u32,
+    // echoed back in every response to this command
+    pub id: u64,
+    pub command: Command,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Status {
+    // command received and being applied
+    Accepted,
+    // publishers connected and publishing
+    Ready { n_publishers: usize },
+    // publishers stopped, with the statistics of each client and the faults injected into them
+    Stopped {
+        publishers: Vec<PublisherStats>,
+        #[serde(default)]
+        faults: Vec<FaultEvent>,
+    },
+    Rejected { reason: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Response {
+    pub version: u32,
+    pub controller_id: String,
+    // id of the command this responds to, None when the command couldn't be parsed
+    pub id: Option<u64>,
+    pub status: Status,
+}
+
+impl CommandMessage {
+    pub fn new(id: u64, command: Command) -> Self {
+        return Self { version: PROTOCOL_VERSION, id, command }
+    }
+
+    pub fn to_message(&self, controller_id: &str) -> Message {
+        return Message::new(command_topic(controller_id), serde_json::to_vec(self).unwrap(), QOS_2)
+    }
+
+    pub fn from_payload(payload: &[u8]) -> Result<Self, String> {
+        let message: Self = serde_json::from_slice(payload).map_err(|e| e.to_string())?;
+        if message.version != PROTOCOL_VERSION {
+            return Err(format!(
+                "unsupported protocol version {} (expected {})", message.version, PROTOCOL_VERSION
+            ))
+        }
+        return Ok(message)
+    }
+}
+
+impl Response {
+    pub fn new(controller_id: &str, id: Option<u64>, status: Status) -> Self {
+        return Self { version: PROTOCOL_VERSION, controller_id: controller_id.to_string(), id, status }
+    }
+
+    pub fn to_message(&self) -> Message {
+        return Message::new(response_topic(&self.controller_id), serde_json::to_vec(self).unwrap(), QOS_2)
+    }
+
+    pub fn from_payload(payload: &[u8]) -> Result<Self, String> {
+        let response: Self = serde_json::from_slice(payload).map_err(|e| e.to_string())?;
+        if response.version != PROTOCOL_VERSION {
+            return Err(format!(
+                "unsupported protocol version {} (expected {})", response.version, PROTOCOL_VERSION
+            ))
+        }
+        return Ok(response)
+    }
+}
+
+// Published periodically by every controller, doubling as its registration with
+// the analyser. The offline variant is the controller's last will
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Heartbeat {
+    pub version: u32,
+    pub controller_id: String,
+    pub online: bool,
+    pub sent_at: DateTime<Utc>,
+}
+
+impl Heartbeat {
+    pub fn new(controller_id: &str, online: bool) -> Self {
+        return Self {
+            version: PROTOCOL_VERSION,
+            controller_id: controller_id.to_string(),
+            online,
+            sent_at: Utc::now(),
+        }
+    }
+
+    pub fn to_message(&self) -> Message {
+        return Message::new(heartbeat_topic(&self.controller_id), serde_json::to_vec(self).unwrap(), QOS_1)
+    }
+
+    // Retained, so an analyser subscribing later still learns the controller is gone
+    pub fn to_will_message(&self) -> Message {
+        return Message::new_retained(heartbeat_topic(&self.controller_id), serde_json::to_vec(self).unwrap(), QOS_1)
+    }
+
+    pub fn from_payload(payload: &[u8]) -> Result<Self, String> {
+        return serde_json::from_slice(payload).map_err(|e| e.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ControllerState {
+    last_seen: Instant,
+    online: bool,
+}
+
+// Discovers controllers and tracks their liveness from heartbeats
+#[derive(Debug)]
+pub struct ControllerMonitor {
+    heartbeat_timeout: Duration,
+    controllers: HashMap<String, ControllerState>,
+    // responses received while waiting on a different controller or command
+    pending: Vec<Response>,
+}
+
+impl ControllerMonitor {
+    pub fn new(heartbeat_timeout: Duration) -> Self {
+        return Self { heartbeat_timeout, controllers: HashMap::new(), pending: Vec::new() }
+    }
+
+    pub fn is_alive(&self, controller_id: &str) -> bool {
+        return self.controllers.get(controller_id)
+            .is_some_and(|x| x.online && x.last_seen.elapsed() < self.heartbeat_timeout)
+    }
+
+    // Ids of all live controllers, sorted so partitioning is stable
+    pub fn alive_controllers(&self) -> Vec<String> {
+        let mut alive = self.controllers.keys()
+            .filter(|x| self.is_alive(x))
+            .cloned()
+            .collect::<Vec<String>>();
+        alive.sort();
+        return alive
+    }
+
+    // Why the controller is considered absent, None while it is alive
+    pub fn absence_reason(&self, controller_id: &str) -> Option<String> {
+        if self.is_alive(controller_id) { return None }
+        return Some(match self.controllers.get(controller_id) {
+            None => format!("no heartbeat received from controller {}", controller_id),
+            Some(state) if !state.online => format!("controller {} reported offline", controller_id),
+            Some(state) => format!(
+                "no heartbeat from controller {} for {}s", controller_id, state.last_seen.elapsed().as_secs()
+            ),
+        })
+    }
+
+    // First absence among the given controllers
+    pub fn any_absent(&self, controller_ids: &[String]) -> Option<String> {
+        return controller_ids.iter().find_map(|x| self.absence_reason(x))
+    }
+
+    // Consumes a control channel message, heartbeats update the liveness state,
+    // responses are handed back to the caller
+    pub fn process(&mut self, message: &Message) -> Option<Response> {
+        if message.topic().starts_with(HEARTBEAT_TOPIC_PREFIX) {
+            if let Ok(heartbeat) = Heartbeat::from_payload(message.payload()) {
+                self.controllers.insert(heartbeat.controller_id, ControllerState {
+                    last_seen: Instant::now(),
+                    online: heartbeat.online,
+                });
+            }
+            return None
+        }
+        if message.topic().starts_with(RESPONSE_TOPIC_PREFIX) {
+            return Response::from_payload(message.payload()).ok()
+        }
+        return None
+    }
+
+    // Waits for the final (non `Accepted`) response of `controller_id` to command `id`,
+    // giving up early if that controller goes away
+    pub async fn await_response(
+        &mut self, resp_stream: &AsyncReceiver<Option<Message>>,
+        controller_id: &str, id: u64, wait: Duration
+    ) -> Result<Status, String> {
+        let is_final = |x: &Response| {
+            x.controller_id == controller_id && x.id == Some(id) && x.status != Status::Accepted
+        };
+        if let Some(position) = self.pending.iter().position(is_final) {
+            return Ok(self.pending.remove(position).status)
+        }
+
+        let deadline = Instant::now() + wait;
+        while Instant::now() < deadline {
+            if let Some(response) = self.poll(resp_stream).await? {
+                if is_final(&response) {
+                    return Ok(response.status)
+                }
+                if response.status != Status::Accepted {
+                    self.pending.push(response);
+                }
+            }
+            if let Some(reason) = self.absence_reason(controller_id) {
+                return Err(reason)
+            }
+        }
+        return Err(format!("no response from controller {} within {}s", controller_id, wait.as_secs()))
+    }
+
+    // Keeps consuming heartbeats for `duration`, returns early once one of the controllers is absent
+    pub async fn watch(
+        &mut self, resp_stream: &AsyncReceiver<Option<Message>>,
+        controller_ids: &[String], duration: Duration
+    ) -> Result<(), String> {
+        let deadline = Instant::now() + duration;
+        while Instant::now() < deadline {
+            if let Some(response) = self.poll(resp_stream).await? {
+                self.pending.push(response);
+            }
+            if let Some(reason) = self.any_absent(controller_ids) {
+                return Err(reason)
+            }
+        }
+        return Ok(())
+    }
+
+    // Waits up to `wait` for at least `min_controllers` live controllers
+    pub async fn await_alive(
+        &mut self, resp_stream: &AsyncReceiver<Option<Message>>,
+        min_controllers: usize, wait: Duration
+    ) -> Result<(), String> {
+        let deadline = Instant::now() + wait;
+        while self.alive_controllers().len() < min_controllers {
+            if Instant::now() >= deadline {
+                return Err(format!(
+                    "{} of {} expected controllers alive", self.alive_controllers().len(), min_controllers
+                ))
+            }
+            if let Some(response) = self.poll(resp_stream).await? {
+                self.pending.push(response);
+            }
+        }
+        return Ok(())
+    }
+
+    async fn poll(&mut self, resp_stream: &AsyncReceiver<Option<Message>>) -> Result<Option<Response>, String> {
+        match timeout(LIVENESS_POLL_INTERVAL, resp_stream.recv()).await {
+            Ok(Ok(Some(resp_msg))) => { return Ok(self.process(&resp_msg)) }
+            Ok(Ok(None)) | Err(_) => { return Ok(None) }
+            Ok(Err(_)) => { return Err("control channel stream closed".to_string()) }
+        }
+    }
+}
+
+// Splits `instancecount` publisher clients over the controllers as evenly as possible,
+// returning (controller id, first index, count), controllers without clients are left out
+pub fn partition(controller_ids: &[String], instancecount: usize) -> Vec<(String, usize, usize)> {
+    let mut assignments = Vec::new();
+    if controller_ids.is_empty() { return assignments }
+
+    let share = instancecount / controller_ids.len();
+    let remainder = instancecount % controller_ids.len();
+    let mut first_index = 0;
+    for (i, controller_id) in controller_ids.iter().enumerate() {
+        let count = share + if i < remainder { 1 } else { 0 };
+        if count > 0 {
+            assignments.push((controller_id.clone(), first_index, count));
+        }
+        first_index += count;
+    }
+    return assignments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(n: usize) -> Vec<String> {
+        return (0..n).map(|x| format!("c{}", x)).collect()
+    }
+
+    #[test]
+    fn partition_spreads_clients_evenly_with_contiguous_indexes() {
+        assert_eq!(partition(&ids(3), 7), vec![
+            ("c0".to_string(), 0, 3), ("c1".to_string(), 3, 2), ("c2".to_string(), 5, 2),
+        ]);
+        assert_eq!(partition(&ids(2), 4), vec![("c0".to_string(), 0, 2), ("c1".to_string(), 2, 2)]);
+    }
+
+    #[test]
+    fn partition_leaves_out_idle_controllers() {
+        assert_eq!(partition(&ids(3), 2), vec![("c0".to_string(), 0, 1), ("c1".to_string(), 1, 1)]);
+        assert!(partition(&ids(3), 0).is_empty());
+        assert!(partition(&[], 5).is_empty());
+    }
+}
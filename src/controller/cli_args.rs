@@ -1,4 +1,5 @@
 use clap::Parser;
+use mqtt_playground::connection::{CredentialArgs, MqttVersion, TlsArgs};
 use lazy_static::lazy_static;
 
 lazy_static! {
@@ -9,4 +10,22 @@ lazy_static! {
 pub struct CliArgs {
     #[arg(short='t', long="TARGET", default_value="mqtt://localhost:1883")]
     pub target_host_uri: String,
+
+    // Controller id, must be unique among the controllers driven by one analyser
+    #[arg(short='n', long="ID", default_value="0")]
+    pub controller_id: String,
+
+    // Interval in-between heartbeats, in seconds
+    #[arg(short='b', long="HEARTBEAT", default_value="2")]
+    pub heartbeat_interval: u64,
+
+    #[command(flatten)]
+    pub creds: CredentialArgs,
+
+    #[command(flatten)]
+    pub tls: TlsArgs,
+
+    // Protocol version of the control channel, publishers use the one requested per run
+    #[arg(long="MQTT_VERSION", value_enum, default_value="3.1.1")]
+    pub mqtt_version: MqttVersion,
 }
\ No newline at end of file
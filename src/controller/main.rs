@@ -1,8 +1,10 @@
-use std::sync::Arc; 
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::Mutex;
+use tokio::time::sleep;
 use tokio::task::JoinHandle;
 use tokio::runtime::Builder;
-use paho_mqtt::{AsyncClient, AsyncReceiver, CreateOptionsBuilder, ConnectOptionsBuilder, Message};
+use paho_mqtt::{AsyncClient, AsyncReceiver, Message};
 
 mod cli_args;
 
@@ -10,6 +12,10 @@ use mqtt_playground::log;
 use mqtt_playground::write_log;
 use mqtt_playground::log::set_tag;
 use mqtt_playground::publisher::Publisher;
+use mqtt_playground::connection::ConnectionConfig;
+use mqtt_playground::command::{Command, CommandMessage, Heartbeat, Response, Status, command_topic};
+use mqtt_playground::stats::PublisherStats;
+use mqtt_playground::fault::FaultEvent;
 use cli_args::CLI_ARGS;
 
 fn main() {
@@ -20,46 +26,60 @@ fn main() {
 
     set_tag("C");
 
-    let mut publisher: Option<Publisher> = None;
-    let mut publisher_handle: Option<JoinHandle<()>> = None;
+    let mut publisher_handle: Option<JoinHandle<(Vec<PublisherStats>, Vec<FaultEvent>)>> = None;
     let mut graceful_stop: Option<Arc<Mutex<bool>>> = None;
 
     let host_uri = &CLI_ARGS.target_host_uri;
+    let controller_id = &CLI_ARGS.controller_id;
+    let creds = match CLI_ARGS.creds.resolve() {
+        Ok(creds) => creds,
+        Err(msg) => {
+            write_log!("Invalid credentials, aborting. [reason={}]\n", msg);
+            return
+        }
+    };
+    let connection = ConnectionConfig::new(host_uri, creds, &CLI_ARGS.tls, CLI_ARGS.mqtt_version);
 
-    write_log!("Starting controller client... [host uri={}]", host_uri);
+    write_log!("Starting controller client... [host uri={}] [controller id={}] [auth={}] [mqtt version={}]", 
+        connection.display_uri(), controller_id, connection.describe_auth(), connection.mqtt_version);
 
     let mut controller_client = AsyncClient::new(
-        CreateOptionsBuilder::new()
-        .client_id("controller")
-        .server_uri(host_uri)
-        .finalize()
+        connection.create_options(&format!("controller_{}", controller_id))
     ).unwrap();
 
-    let mut qos: Option<i32> = None;
-    let mut delay: Option<u64> = None;
-    let mut instancecount: Option<usize> = None;
-
     main_rt.block_on(async {
-        let mut resp_stream: AsyncReceiver<Option<Message>> = controller_client.get_stream(32);
+        let resp_stream: AsyncReceiver<Option<Message>> = controller_client.get_stream(32);
 
         controller_client.connect(
-            ConnectOptionsBuilder::new()
-            .user_name("user")
-            .password("123")
+            connection.connect_options().unwrap()
+            .will_message(Heartbeat::new(controller_id, false).to_will_message())
+            // the control channel has to outlive broker restarts
+            .automatic_reconnect(Duration::from_secs(1), Duration::from_secs(30))
             .finalize()
         ).await.unwrap();
 
         write_log!("Controller connected.");
 
-        controller_client.subscribe_many(&[
-            "request/qos", "request/delay", "request/instancecount", "request/reset", "request/killall"
-        ], &[2, 2, 2, 2, 2]).await.unwrap();
+        let heartbeat_client = controller_client.clone();
+        let heartbeat_handle = main_rt.spawn(async move {
+            loop {
+                let _ = heartbeat_client.publish(Heartbeat::new(&CLI_ARGS.controller_id, true).to_message()).await;
+                sleep(Duration::from_secs(CLI_ARGS.heartbeat_interval)).await;
+            }
+        });
 
-        write_log!("Controller subscribed.\n");
+        controller_client.subscribe(command_topic(controller_id), 2).await.unwrap();
+        controller_client.set_connected_callback(|client| {
+            let _ = client.subscribe(command_topic(&CLI_ARGS.controller_id), 2);
+        });
 
-        let mut reset = false;
+        write_log!("Controller subscribed.\n");
 
-        while let Ok(Some(resp_msg)) = resp_stream.recv().await {
+        while let Ok(resp_msg) = resp_stream.recv().await {
+            let Some(resp_msg) = resp_msg else {
+                write_log!("Controller connection lost, reconnecting...\n");
+                continue;
+            };
             println!(r#"Message received. [
     topic={},
     payload={},
@@ -67,84 +87,100 @@ fn main() {
 ]
             "#, resp_msg.topic(), resp_msg.payload_str(), resp_msg.qos());
 
-            match resp_msg.topic() {
-                "request/qos" => {
-                    // write_log!("Message from topic qos received. [\n    payload={},\n]\n", resp_msg.payload_str());
-                    if let Ok(new_qos) = resp_msg.payload_str().parse::<i32>() {
-                        qos = Some(new_qos);
-                    }
-                }
-                "request/delay" => {
-                    // write_log!("Message from topic delay received. [\n    payload={},\n]\n", resp_msg.payload_str());
-                    if let Ok(new_delay) = resp_msg.payload_str().parse::<u64>() {
-                        delay = Some(new_delay);
-                    }
+            let command = match CommandMessage::from_payload(resp_msg.payload()) {
+                Ok(command) => command,
+                Err(msg) => {
+                    write_log!("Malformed command rejected. [reason={}]\n", msg);
+                    respond(&controller_client, Response::new(controller_id, None, Status::Rejected { reason: msg })).await;
+                    continue;
                 }
-                "request/instancecount" => {
-                    // write_log!("Message from topic instancecount received. [\n    payload={},\n]\n", resp_msg.payload_str());
-                    if let Ok(new_count) = resp_msg.payload_str().parse::<usize>() {
-                        instancecount = Some(new_count);
+            };
+
+            respond(&controller_client, Response::new(controller_id, Some(command.id), Status::Accepted)).await;
+
+            // every command starts by stopping whatever is currently running
+            let (publishers, faults) = stop_publishers(&mut graceful_stop, &mut publisher_handle).await;
+
+            match command.command {
+                Command::Start(config) => {
+                    write_log!("Parameter updated, preparing new publisher task. [\n    qos={}\n    delay={}\n    instancecount={}\n    first_index={}\n    tls={}\n    mqtt_version={}\n    payload={}x{}B\n    profile={}\n    session={}\n    reconnect={}\n    faults={:?}\n]\n", config.qos, config.delay, config.instancecount, config.first_index, config.tls, config.mqtt_version, config.payload_kind, config.payload_size, config.profile.as_ref().map(|x| x.to_string()).unwrap_or("none".to_string()), config.session.mode, config.session.reconnect.is_some(), config.faults.iter().map(|x| x.to_string()).collect::<Vec<String>>());
+
+                    let connected = match if config.tls { connection.to_tls() } else { Ok(connection.clone()) } {
+                        Ok(run_connection) => Publisher::connect(
+                            &run_connection.with_version(config.mqtt_version).with_session(config.session),
+                            controller_id, config.first_index,
+                            config.instancecount, config.delay, config.qos, config.retain,
+                            config.payload_kind, config.payload_size, config.profile.clone(), config.faults.clone()
+                        ).await,
+                        Err(msg) => Err(msg),
+                    };
+                    match connected {
+                        Ok(mut new_publisher) => {
+                            graceful_stop = Some(Arc::clone(&new_publisher.make_graceful_stop));
+
+                            write_log!("Starting new publisher clients...\n");
+
+                            let new_instancecount = config.instancecount;
+
+                            publisher_handle = Some(main_rt.spawn(async move {
+                                let stats = new_publisher.start().await;
+                                let faults = new_publisher.faults.lock().await.clone();
+                                let total_n_messages = stats.iter().map(|x| x.n_messages_sent).sum::<u64>();
+                                write_log!(r#"Publisher clients 0..{} terminated. [
+    total number of messages sent={}
+]
+                                "#, new_instancecount, total_n_messages);
+                                (stats, faults)
+                            }));
+
+                            respond(&controller_client, Response::new(
+                                controller_id, Some(command.id), Status::Ready { n_publishers: config.instancecount }
+                            )).await;
+                        }
+                        Err(msg) => {
+                            respond(&controller_client, Response::new(
+                                controller_id, Some(command.id), Status::Rejected { reason: format!("publishers failed to connect: {}", msg) }
+                            )).await;
+                        }
                     }
                 }
-                "request/reset" => { 
-                    if graceful_stop.is_some() {
-                        write_log!("Terminating publisher clients...\n");
-                        *graceful_stop.as_mut().unwrap().lock().await = true;
-                    }
-                    graceful_stop = None;
-                    reset = !reset; 
+                Command::Stop => {
+                    respond(&controller_client, Response::new(
+                        controller_id, Some(command.id), Status::Stopped { publishers, faults }
+                    )).await;
                 }
-                "request/killall" => {
-                    if graceful_stop.is_some() {
-                        write_log!("Terminating publisher clients...\n");
-                        *graceful_stop.as_mut().unwrap().lock().await = true;
-                        if publisher_handle.is_some() {
-                            publisher_handle.as_mut().unwrap().await.unwrap();
-                        }
-                    }
+                Command::KillAll => {
+                    respond(&controller_client, Response::new(
+                        controller_id, Some(command.id), Status::Stopped { publishers, faults }
+                    )).await;
+                    // a clean disconnect doesn't fire the last will, announce it explicitly
+                    heartbeat_handle.abort();
+                    let _ = controller_client.publish(Heartbeat::new(controller_id, false).to_will_message()).await;
+                    let _ = controller_client.disconnect(None).await;
                     return
                 }
-                _ => {}
-            }
-
-            if reset {
-                if let (Some(new_qos), Some(new_delay), Some(new_count)) = 
-                    (qos.as_ref(), delay.as_ref(), instancecount.as_ref()) {
-                    write_log!("Parameter updated, preparing new publisher task. [\n    qos={}\n    delay={}\n    instancecount={}\n]\n", new_qos, new_delay, new_count);
-
-                    if graceful_stop.is_some() {
-                        write_log!("Terminating publisher clients...\n");
-                        *graceful_stop.as_mut().unwrap().lock().await = true;
-                        if publisher_handle.is_some() {
-                            publisher_handle.as_mut().unwrap().await.unwrap();
-                        }
-                    }
-
-                    if let Ok(new_publisher) = Publisher::connect(
-                        host_uri, Some(("user", "123")),
-                        new_count.clone(), new_delay.clone(), new_qos.clone()
-                    ).await {
-                        publisher = Some(new_publisher);
-                        graceful_stop = Some(Arc::clone(&(publisher.as_ref().unwrap()).make_graceful_stop));
-
-                        write_log!("Starting new publisher clients...\n");
-
-                        let new_instancecount = new_count.clone();
-
-                        publisher_handle = Some(main_rt.spawn(async move {
-                            let stats = publisher.unwrap().start().await;
-                            let total_n_messages = stats.iter().sum::<u64>();
-                            write_log!(r#"Publisher clients 0..{} terminated. [
-    total number of messages sent={}
-]
-                            "#, new_instancecount, total_n_messages);
-                        }));
-                    } else {
-                        publisher = None;
-                        graceful_stop = None;
-                    }
-                }
             }
         }
     });
-}
\ No newline at end of file
+}
+
+// Stops the running publishers, if any, and waits for them to report their statistics
+async fn stop_publishers(
+    graceful_stop: &mut Option<Arc<Mutex<bool>>>,
+    publisher_handle: &mut Option<JoinHandle<(Vec<PublisherStats>, Vec<FaultEvent>)>>,
+) -> (Vec<PublisherStats>, Vec<FaultEvent>) {
+    if let Some(stop) = graceful_stop.take() {
+        write_log!("Terminating publisher clients...\n");
+        *stop.lock().await = true;
+    }
+    if let Some(handle) = publisher_handle.take() {
+        return handle.await.unwrap_or_default()
+    }
+    return (Vec::new(), Vec::new())
+}
+
+async fn respond(controller_client: &AsyncClient, response: Response) {
+    if let Err(msg) = controller_client.publish(response.to_message()).await {
+        write_log!("Failed to publish response. [reason={}]\n", msg);
+    }
+}
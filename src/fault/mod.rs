@@ -0,0 +1,268 @@
+use chrono::{DateTime, Utc};
+use paho_mqtt::{AsyncClient, ConnectOptions};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::{process, sync::Mutex, task::JoinHandle, time::{sleep, sleep_until, Instant}};
+
+use crate::log;
+use crate::write_log;
+use crate::connection::{ConnectionEvents, describe_error};
+
+// What a fault hits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FaultTarget {
+    Publishers,
+    Subscribers,
+    Broker,
+}
+
+impl fmt::Display for FaultTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            FaultTarget::Publishers => write!(f, "publishers"),
+            FaultTarget::Subscribers => write!(f, "subscribers"),
+            FaultTarget::Broker => write!(f, "broker"),
+        }
+    }
+}
+
+// Fault injected `at_s` into the run and every `every_s` after that when set: `fraction` of the
+// target's clients are disconnected and reconnect `down_s` later. Broker faults run the analyser's
+// --BROKER_RESTART_CMD instead, fraction and down time don't apply to them
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FaultSpec {
+    pub target: FaultTarget,
+    pub fraction: f64,
+    pub at_s: f64,
+    pub down_s: f64,
+    pub every_s: Option<f64>,
+}
+
+impl FaultSpec {
+    // Number of clients out of `n_clients` hit by every occurrence, at least one
+    pub fn n_hit(&self, n_clients: usize) -> usize {
+        return ((self.fraction * n_clients as f64).ceil() as usize).clamp(1, n_clients.max(1))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let valid = self.fraction > 0.0 && self.fraction <= 1.0 && self.at_s >= 0.0 && self.down_s >= 0.0
+            && self.every_s.map(|x| x > self.down_s).unwrap_or(true);
+        if !valid {
+            return Err(format!("invalid fault {}, the period has to be longer than the down time", self))
+        }
+        return Ok(())
+    }
+}
+
+// Compact form used on the command line, e.g. "subscribers:0.5:10:2:20"
+impl fmt::Display for FaultSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.target == FaultTarget::Broker {
+            write!(f, "broker:{}", self.at_s)?;
+        } else {
+            write!(f, "{}:{}:{}:{}", self.target, self.fraction, self.at_s, self.down_s)?;
+        }
+        if let Some(every_s) = self.every_s {
+            write!(f, ":{}", every_s)?;
+        }
+        return Ok(())
+    }
+}
+
+impl FromStr for FaultSpec {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let fields = spec.split(':').collect::<Vec<&str>>();
+        let number = |i: usize| -> Result<f64, String> {
+            return fields.get(i)
+                .ok_or(format!("missing field {} in fault \"{}\"", i, spec))?
+                .parse::<f64>()
+                .map_err(|e| format!("invalid field {} in fault \"{}\": {}", i, spec, e))
+        };
+
+        let fault = match (fields[0], fields.len()) {
+            ("broker", 2 | 3) => FaultSpec {
+                target: FaultTarget::Broker,
+                fraction: 1.0,
+                at_s: number(1)?,
+                down_s: 0.0,
+                every_s: if fields.len() == 3 { Some(number(2)?) } else { None },
+            },
+            ("publishers" | "subscribers", 4 | 5) => FaultSpec {
+                target: if fields[0] == "publishers" { FaultTarget::Publishers } else { FaultTarget::Subscribers },
+                fraction: number(1)?,
+                at_s: number(2)?,
+                down_s: number(3)?,
+                every_s: if fields.len() == 5 { Some(number(4)?) } else { None },
+            },
+            _ => {
+                return Err(format!(
+                    "unknown fault \"{}\", expected publishers:<fraction>:<at s>:<down s>[:<every s>], \
+                    subscribers:<fraction>:<at s>:<down s>[:<every s>] or broker:<at s>[:<every s>]", spec
+                ))
+            }
+        };
+        fault.validate()?;
+        return Ok(fault)
+    }
+}
+
+// One injected fault, as it happened
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FaultEvent {
+    pub target: FaultTarget,
+    pub at: DateTime<Utc>,
+    // global indexes of the clients disconnected, empty for broker faults
+    pub clients: Vec<usize>,
+    // from the first disconnect until the last reconnect, or the restart command's runtime
+    pub down_ms: u64,
+    // clients that failed to disconnect or reconnect, or why the restart command failed
+    pub errors: Vec<String>,
+}
+
+// Starts one task per fault in `specs` aimed at `target`, disconnecting `clients` on schedule. The tasks
+// run until aborted and append every fault to `log`, `first_index` is the global index of the first client
+pub fn spawn_client_faults(
+    specs: &[FaultSpec], target: FaultTarget,
+    clients: &Arc<Mutex<Vec<AsyncClient>>>, events: &[Arc<ConnectionEvents>], first_index: usize,
+    connect_options: &ConnectOptions, log: &Arc<Mutex<Vec<FaultEvent>>>
+) -> Vec<JoinHandle<()>> {
+    let started_at = Instant::now();
+    return specs.iter().filter(|x| x.target == target).map(|spec| {
+        let spec = spec.clone();
+        let clients = Arc::clone(clients);
+        let events = events.to_vec();
+        let connect_options = connect_options.clone();
+        let log = Arc::clone(log);
+        tokio::spawn(async move {
+            let n_hit = spec.n_hit(events.len());
+            let mut next = started_at + Duration::from_secs_f64(spec.at_s);
+            let mut n_injected = 0;
+            loop {
+                sleep_until(next).await;
+                // rotates through the clients so repeated faults hit different ones
+                let hit = (0..n_hit).map(|k| (n_injected * n_hit + k) % events.len()).collect::<Vec<usize>>();
+                let fault = disconnect(
+                    &clients, &events, &hit, first_index, target, Duration::from_secs_f64(spec.down_s), &connect_options
+                ).await;
+                write_log!("Fault injected. [target={}] [clients={:?}] [down_ms={}] [errors={:?}]\n",
+                    target, fault.clients, fault.down_ms, fault.errors);
+                log.lock().await.push(fault);
+                n_injected += 1;
+                match spec.every_s {
+                    Some(every_s) => { next += Duration::from_secs_f64(every_s); }
+                    None => { break; }
+                }
+            }
+        })
+    }).collect()
+}
+
+// Starts one task per broker fault in `specs`, each running `restart_command` through the shell on
+// schedule. The command is expected to return once the broker accepts connections again
+pub fn spawn_broker_faults(specs: &[FaultSpec], restart_command: &str, log: &Arc<Mutex<Vec<FaultEvent>>>) -> Vec<JoinHandle<()>> {
+    let started_at = Instant::now();
+    return specs.iter().filter(|x| x.target == FaultTarget::Broker).map(|spec| {
+        let spec = spec.clone();
+        let restart_command = restart_command.to_string();
+        let log = Arc::clone(log);
+        tokio::spawn(async move {
+            let mut next = started_at + Duration::from_secs_f64(spec.at_s);
+            loop {
+                sleep_until(next).await;
+                let at = Utc::now();
+                let restarted_at = Instant::now();
+                let errors = match process::Command::new("sh").arg("-c").arg(&restart_command).status().await {
+                    Ok(status) if status.success() => Vec::new(),
+                    Ok(status) => vec![format!("restart command failed: {}", status)],
+                    Err(e) => vec![format!("restart command failed: {}", e)],
+                };
+                let fault = FaultEvent {
+                    target: FaultTarget::Broker,
+                    at: at,
+                    clients: Vec::new(),
+                    down_ms: restarted_at.elapsed().as_millis() as u64,
+                    errors: errors,
+                };
+                write_log!("Fault injected. [target=broker] [down_ms={}] [errors={:?}]\n", fault.down_ms, fault.errors);
+                log.lock().await.push(fault);
+                match spec.every_s {
+                    Some(every_s) => { next += Duration::from_secs_f64(every_s); }
+                    None => { break; }
+                }
+            }
+        })
+    }).collect()
+}
+
+// Disconnects the `hit` clients, waits `down` and connects them again with their original options,
+// an explicitly disconnected client neither reconnects automatically nor buffers publishes meanwhile
+async fn disconnect(
+    clients: &Arc<Mutex<Vec<AsyncClient>>>, events: &[Arc<ConnectionEvents>], hit: &[usize],
+    first_index: usize, target: FaultTarget, down: Duration, connect_options: &ConnectOptions
+) -> FaultEvent {
+    let at = Utc::now();
+    let started_at = Instant::now();
+    let mut errors = Vec::new();
+    for &i in hit {
+        // cloned so the clients aren't locked while waiting on the broker
+        let client = (*clients.lock().await)[i].clone();
+        events[i].mark_lost();
+        if let Err(e) = client.disconnect(None).await {
+            errors.push(format!("client {} disconnect: {}", first_index + i, describe_error(&e)));
+        }
+    }
+    sleep(down).await;
+    for &i in hit {
+        let client = (*clients.lock().await)[i].clone();
+        if let Err(e) = client.connect(connect_options.clone()).await {
+            errors.push(format!("client {} reconnect: {}", first_index + i, describe_error(&e)));
+        }
+    }
+    return FaultEvent {
+        target: target,
+        at: at,
+        clients: hit.iter().map(|x| first_index + x).collect(),
+        down_ms: started_at.elapsed().as_millis() as u64,
+        errors: errors,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn faults_round_trip_through_their_compact_form() {
+        for spec in ["subscribers:0.5:10:2:20", "publishers:1:30:5", "broker:45", "broker:45:60"] {
+            let fault = spec.parse::<FaultSpec>().unwrap();
+            assert_eq!(fault.to_string(), spec);
+            assert_eq!(fault.to_string().parse::<FaultSpec>().unwrap(), fault);
+        }
+        assert_eq!("broker:45".parse::<FaultSpec>().unwrap().target, FaultTarget::Broker);
+    }
+
+    #[test]
+    fn malformed_faults_are_rejected() {
+        for spec in [
+            "", "broker", "broker:x", "broker:1:2:3", "publishers:0.5:10", "publishers:0:10:2", "publishers:1.5:10:2",
+            "subscribers:0.5:-1:2", "subscribers:0.5:10:-2", "subscribers:0.5:10:5:5", "clients:0.5:10:2",
+        ] {
+            assert!(spec.parse::<FaultSpec>().is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn every_fault_hits_at_least_one_client() {
+        let fault = "publishers:0.3:0:1".parse::<FaultSpec>().unwrap();
+        assert_eq!(fault.n_hit(10), 3);
+        assert_eq!(fault.n_hit(4), 2);
+        assert_eq!(fault.n_hit(1), 1);
+        assert_eq!("publishers:1:0:1".parse::<FaultSpec>().unwrap().n_hit(7), 7);
+    }
+}
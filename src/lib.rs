@@ -1,3 +1,11 @@
+pub mod broker;
+pub mod command;
+pub mod connection;
+pub mod fault;
 pub mod log;
+pub mod payload;
+pub mod profile;
 pub mod publisher;
+pub mod report;
+pub mod stats;
 pub mod subscriber;
\ No newline at end of file
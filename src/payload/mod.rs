@@ -0,0 +1,253 @@
+use chrono::Utc;
+use clap::ValueEnum;
+use paho_mqtt::{Message, MessageBuilder, Properties, PropertyCode};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// Current version of the payload header layout
+pub const HEADER_VERSION: u8 = 2;
+
+// Separates the header fields, the header itself is terminated by a newline
+const FIELD_SEPARATOR: char = ';';
+const HEADER_TERMINATOR: u8 = b'\n';
+
+// Compressible filler for text bodies
+const TEXT_FILLER: &str = "Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do eiusmod tempor incididunt ut labore et dolore magna aliqua. ";
+
+// One record of the JSON body, repeated until the requested size is reached
+const JSON_RECORD: &str = r#"{"sensor":"temperature","location":"hall-3","value":21.5,"unit":"C","status":"ok"}"#;
+
+// User property keys carrying the header fields over MQTT v5
+const PROPERTY_VERSION: &str = "v";
+const PROPERTY_SEQ: &str = "seq";
+const PROPERTY_PUBLISHER_ID: &str = "publisher_id";
+const PROPERTY_EPOCH: &str = "epoch";
+const PROPERTY_SENT_AT: &str = "sent_at_us";
+
+// Header prepended to every message sent by a publisher client.
+// Layout (text, version 2): "v2;<seq>;<publisher id>;<epoch>;<send timestamp in us>\n<body>"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PayloadHeader {
+    pub version: u8,
+    pub seq: u64,
+    pub publisher_id: String,
+    // identifies the run of the publisher's counter, a newer epoch restarts the sequence
+    pub epoch: u64,
+    pub sent_at_us: i64,
+}
+
+impl PayloadHeader {
+    // Header stamped with the current time
+    pub fn new(seq: u64, publisher_id: &str, epoch: u64) -> Self {
+        return Self {
+            version: HEADER_VERSION,
+            seq: seq,
+            publisher_id: publisher_id.to_string(),
+            epoch: epoch,
+            sent_at_us: now_micros(),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buff = format!(
+            "v{}{sep}{}{sep}{}{sep}{}{sep}{}",
+            self.version, self.seq, self.publisher_id, self.epoch, self.sent_at_us,
+            sep = FIELD_SEPARATOR
+        ).into_bytes();
+        buff.push(HEADER_TERMINATOR);
+        return buff
+    }
+
+    // Splits a received payload into its header and the remaining body
+    pub fn decode(payload: &[u8]) -> Option<(Self, &[u8])> {
+        let end = payload.iter().position(|&b| b == HEADER_TERMINATOR)?;
+        let header = std::str::from_utf8(&payload[..end]).ok()?;
+        let mut fields = header.split(FIELD_SEPARATOR);
+
+        let version = fields.next()?.strip_prefix('v')?.parse::<u8>().ok()?;
+        if version != HEADER_VERSION { return None; }
+        let seq = fields.next()?.parse::<u64>().ok()?;
+        let publisher_id = fields.next()?.to_string();
+        let epoch = fields.next()?.parse::<u64>().ok()?;
+        let sent_at_us = fields.next()?.parse::<i64>().ok()?;
+        if fields.next().is_some() { return None; }
+
+        return Some((
+            Self { version, seq, publisher_id, epoch, sent_at_us },
+            &payload[end + 1..]
+        ))
+    }
+
+    // Same fields as user properties, leaving the payload to the body (MQTT v5 only)
+    pub fn to_properties(&self) -> Properties {
+        let mut props = Properties::new();
+        let _ = props.push_string_pair(PropertyCode::UserProperty, PROPERTY_VERSION, &self.version.to_string());
+        let _ = props.push_string_pair(PropertyCode::UserProperty, PROPERTY_SEQ, &self.seq.to_string());
+        let _ = props.push_string_pair(PropertyCode::UserProperty, PROPERTY_PUBLISHER_ID, &self.publisher_id);
+        let _ = props.push_string_pair(PropertyCode::UserProperty, PROPERTY_EPOCH, &self.epoch.to_string());
+        let _ = props.push_string_pair(PropertyCode::UserProperty, PROPERTY_SENT_AT, &self.sent_at_us.to_string());
+        return props
+    }
+
+    pub fn from_properties(props: &Properties) -> Option<Self> {
+        let version = props.find_user_property(PROPERTY_VERSION)?.parse::<u8>().ok()?;
+        if version != HEADER_VERSION { return None; }
+        return Some(Self {
+            version,
+            seq: props.find_user_property(PROPERTY_SEQ)?.parse::<u64>().ok()?,
+            publisher_id: props.find_user_property(PROPERTY_PUBLISHER_ID)?,
+            epoch: props.find_user_property(PROPERTY_EPOCH)?.parse::<u64>().ok()?,
+            sent_at_us: props.find_user_property(PROPERTY_SENT_AT)?.parse::<i64>().ok()?,
+        })
+    }
+
+    // One-way latency from publish to `received_at_us`, clamped at 0 to absorb clock skew
+    pub fn latency_us(&self, received_at_us: i64) -> u64 {
+        return (received_at_us - self.sent_at_us).max(0) as u64
+    }
+}
+
+// Wall clock timestamp in microseconds, shared by publishers and subscribers
+pub fn now_micros() -> i64 {
+    return Utc::now().timestamp_micros()
+}
+
+// Message carrying `header` and `body`, with the header in user properties when
+// `in_properties` is set (MQTT v5) and prepended to the payload otherwise
+pub fn build_message(topic: &str, header: &PayloadHeader, body: &[u8], qos: i32, retain: bool, in_properties: bool) -> Message {
+    if in_properties {
+        return MessageBuilder::new()
+            .topic(topic)
+            .payload(body)
+            .qos(qos)
+            .retained(retain)
+            .properties(header.to_properties())
+            .finalize()
+    }
+    let mut payload = header.encode();
+    payload.extend_from_slice(body);
+    if retain {
+        return Message::new_retained(topic, payload, qos)
+    }
+    return Message::new(topic, payload, qos)
+}
+
+// Header and body of a received message, wherever the publisher put the header
+pub fn read_message(message: &Message) -> Option<(PayloadHeader, &[u8])> {
+    if let Some(header) = PayloadHeader::from_properties(message.properties()) {
+        return Some((header, message.payload()))
+    }
+    return PayloadHeader::decode(message.payload())
+}
+
+// Content of the message body following the sequence header
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadKind {
+    // incompressible pseudo-random bytes
+    #[default]
+    Random,
+    // repeated plain text
+    Text,
+    // JSON array of fixed sensor records
+    Json,
+}
+
+impl fmt::Display for PayloadKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            PayloadKind::Random => write!(f, "random"),
+            PayloadKind::Text => write!(f, "text"),
+            PayloadKind::Json => write!(f, "json"),
+        }
+    }
+}
+
+// Body of exactly `size` bytes, the sequence header comes on top of it. `seed` only
+// matters to random bodies, so clients don't all send the same bytes
+pub fn generate_body(kind: PayloadKind, size: usize, seed: u64) -> Vec<u8> {
+    return match kind {
+        PayloadKind::Random => {
+            // xorshift64, plenty for defeating compression
+            let mut state = seed | 1;
+            let mut body = Vec::with_capacity(size + 8);
+            while body.len() < size {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                body.extend_from_slice(&state.to_le_bytes());
+            }
+            body.truncate(size);
+            body
+        }
+        PayloadKind::Text => TEXT_FILLER.bytes().cycle().take(size).collect(),
+        PayloadKind::Json => {
+            if size < 2 {
+                return vec![b' '; size]
+            }
+            let mut body = b"[".to_vec();
+            let mut n_records = 0;
+            while body.len() + JSON_RECORD.len() + 2 <= size {
+                if n_records > 0 { body.push(b','); }
+                body.extend_from_slice(JSON_RECORD.as_bytes());
+                n_records += 1;
+            }
+            body.push(b']');
+            // whitespace padding keeps the document valid
+            body.resize(size, b' ');
+            body
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_round_trips_with_its_body() {
+        let header = PayloadHeader { version: HEADER_VERSION, seq: 42, publisher_id: "publisher_a_3".to_string(), epoch: 7, sent_at_us: -5 };
+        let mut payload = header.encode();
+        payload.extend_from_slice(b"body;with\nseparators");
+        let (decoded, body) = PayloadHeader::decode(&payload).unwrap();
+        assert_eq!(decoded, header);
+        assert_eq!(body, b"body;with\nseparators");
+
+        let encoded = header.encode();
+        assert!(PayloadHeader::decode(&encoded).unwrap().1.is_empty());
+    }
+
+    #[test]
+    fn malformed_headers_are_rejected() {
+        for payload in [
+            "",
+            "v2;1;p;1;100",
+            "v1;1;p;100\n",
+            "v2;1;p;1;100;extra\n",
+            "v2;x;p;1;100\n",
+            "v2;1;p;-1;100\n",
+            "v2;1;p;1\n",
+            "2;1;p;1;100\n",
+            "v2;1;p;1;100ms\n",
+        ] {
+            assert!(PayloadHeader::decode(payload.as_bytes()).is_none(), "{:?}", payload);
+        }
+        assert!(PayloadHeader::decode(b"v2;1;p;1;\xff\n").is_none());
+    }
+
+    #[test]
+    fn latency_is_clamped_at_zero() {
+        let header = PayloadHeader { version: HEADER_VERSION, seq: 0, publisher_id: "p".to_string(), epoch: 0, sent_at_us: 1_000 };
+        assert_eq!(header.latency_us(1_250), 250);
+        assert_eq!(header.latency_us(900), 0);
+    }
+
+    #[test]
+    fn bodies_have_the_requested_size() {
+        for kind in [PayloadKind::Random, PayloadKind::Text, PayloadKind::Json] {
+            for size in [0, 1, 100, 4096] {
+                assert_eq!(generate_body(kind, size, 1).len(), size, "{} {}", kind, size);
+            }
+        }
+    }
+}
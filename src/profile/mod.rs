@@ -0,0 +1,189 @@
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+
+// Rate over time of one publisher client, in messages per second. Rates below zero
+// (e.g. a sinusoid with an amplitude above its mean) are clamped to zero
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LoadProfile {
+    Constant { rate: f64 },
+    // linear from `from` to `to` over `duration_s`, then holds `to`
+    Ramp { from: f64, to: f64, duration_s: f64 },
+    // each rate for `step_s`, then holds the last one
+    Step { rates: Vec<f64>, step_s: f64 },
+    Sinusoidal { mean: f64, amplitude: f64, period_s: f64 },
+    // `size` messages back to back every `every_s`
+    Burst { size: u64, every_s: f64 },
+}
+
+impl LoadProfile {
+    // Instantaneous rate `elapsed_s` into the run, bursts are spread over their period
+    pub fn rate_at(&self, elapsed_s: f64) -> f64 {
+        let rate = match self {
+            LoadProfile::Constant { rate } => *rate,
+            LoadProfile::Ramp { from, to, duration_s } => {
+                if elapsed_s >= *duration_s { *to } else { from + (to - from) * elapsed_s / duration_s }
+            }
+            LoadProfile::Step { rates, step_s } => {
+                let step = (elapsed_s / step_s.max(f64::MIN_POSITIVE)) as usize;
+                rates.get(step).or(rates.last()).copied().unwrap_or(0.0)
+            }
+            LoadProfile::Sinusoidal { mean, amplitude, period_s } => {
+                mean + amplitude * (2.0 * PI * elapsed_s / period_s).sin()
+            }
+            LoadProfile::Burst { size, every_s } => *size as f64 / every_s,
+        };
+        return rate.max(0.0)
+    }
+
+    // Number of messages the profile asks for over the first `elapsed_s` seconds
+    pub fn expected_messages(&self, elapsed_s: f64) -> f64 {
+        return match self {
+            LoadProfile::Constant { rate } => rate * elapsed_s,
+            LoadProfile::Ramp { from, to, duration_s } => {
+                let ramp_s = elapsed_s.min(*duration_s);
+                from * ramp_s + (to - from) * ramp_s * ramp_s / (2.0 * duration_s)
+                    + to * (elapsed_s - duration_s).max(0.0)
+            }
+            LoadProfile::Step { rates, step_s } => {
+                let mut expected = 0.0;
+                let mut remaining_s = elapsed_s;
+                for (i, rate) in rates.iter().enumerate() {
+                    let span_s = if i + 1 == rates.len() { remaining_s } else { remaining_s.min(*step_s) };
+                    expected += rate * span_s;
+                    remaining_s -= span_s;
+                    if remaining_s <= 0.0 { break; }
+                }
+                expected
+            }
+            LoadProfile::Sinusoidal { mean, amplitude, period_s } => {
+                // ignores the clamping at zero, exact as long as amplitude <= mean
+                mean * elapsed_s + amplitude * period_s / (2.0 * PI) * (1.0 - (2.0 * PI * elapsed_s / period_s).cos())
+            }
+            LoadProfile::Burst { size, every_s } => {
+                (*size as f64) * ((elapsed_s / every_s).floor() + 1.0)
+            }
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        // an infinite rate would make the pacer spin
+        let finite = |xs: &[f64]| xs.iter().all(|x| x.is_finite());
+        let valid = match self {
+            LoadProfile::Constant { rate } => finite(&[*rate]) && *rate > 0.0,
+            LoadProfile::Ramp { from, to, duration_s } => {
+                finite(&[*from, *to, *duration_s]) && *from >= 0.0 && *to >= 0.0 && *duration_s > 0.0
+            }
+            LoadProfile::Step { rates, step_s } => {
+                finite(rates) && finite(&[*step_s]) && !rates.is_empty() && rates.iter().all(|&x| x >= 0.0) && *step_s > 0.0
+            }
+            LoadProfile::Sinusoidal { mean, amplitude, period_s } => {
+                finite(&[*mean, *amplitude, *period_s]) && *mean > 0.0 && *period_s > 0.0
+            }
+            LoadProfile::Burst { size, every_s } => finite(&[*every_s]) && *size > 0 && *every_s > 0.0,
+        };
+        if !valid {
+            return Err(format!("invalid load profile {}", self))
+        }
+        return Ok(())
+    }
+}
+
+// Compact form used on the command line, e.g. "ramp:100:5000:30"
+impl fmt::Display for LoadProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            LoadProfile::Constant { rate } => write!(f, "constant:{}", rate),
+            LoadProfile::Ramp { from, to, duration_s } => write!(f, "ramp:{}:{}:{}", from, to, duration_s),
+            LoadProfile::Step { rates, step_s } => write!(
+                f, "step:{}:{}", rates.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(","), step_s
+            ),
+            LoadProfile::Sinusoidal { mean, amplitude, period_s } => write!(f, "sine:{}:{}:{}", mean, amplitude, period_s),
+            LoadProfile::Burst { size, every_s } => write!(f, "burst:{}:{}", size, every_s),
+        }
+    }
+}
+
+impl FromStr for LoadProfile {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let fields = spec.split(':').collect::<Vec<&str>>();
+        let number = |i: usize| -> Result<f64, String> {
+            return fields.get(i)
+                .ok_or(format!("missing field {} in load profile \"{}\"", i, spec))?
+                .parse::<f64>()
+                .map_err(|e| format!("invalid field {} in load profile \"{}\": {}", i, spec, e))
+        };
+
+        let profile = match (fields[0], fields.len()) {
+            ("constant", 2) => LoadProfile::Constant { rate: number(1)? },
+            ("ramp", 4) => LoadProfile::Ramp { from: number(1)?, to: number(2)?, duration_s: number(3)? },
+            ("step", 3) => LoadProfile::Step {
+                rates: fields[1].split(',')
+                    .map(|x| x.parse::<f64>().map_err(|e| format!("invalid rate in load profile \"{}\": {}", spec, e)))
+                    .collect::<Result<Vec<f64>, String>>()?,
+                step_s: number(2)?,
+            },
+            ("sine", 4) => LoadProfile::Sinusoidal { mean: number(1)?, amplitude: number(2)?, period_s: number(3)? },
+            ("burst", 3) => LoadProfile::Burst {
+                size: fields[1].parse::<u64>().map_err(|e| format!("invalid burst size in load profile \"{}\": {}", spec, e))?,
+                every_s: number(2)?,
+            },
+            _ => {
+                return Err(format!(
+                    "unknown load profile \"{}\", expected constant:<rate>, ramp:<from>:<to>:<duration s>, \
+                    step:<rate,rate,...>:<step s>, sine:<mean>:<amplitude>:<period s> or burst:<size>:<every s>", spec
+                ))
+            }
+        };
+        profile.validate()?;
+        return Ok(profile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profiles_round_trip_through_their_compact_form() {
+        for spec in ["constant:100", "ramp:100:5000:30", "step:100,200,0:10", "sine:1000:500:60", "burst:50:2.5"] {
+            let profile = spec.parse::<LoadProfile>().unwrap();
+            assert_eq!(profile.to_string(), spec);
+            assert_eq!(profile.to_string().parse::<LoadProfile>().unwrap(), profile);
+        }
+    }
+
+    #[test]
+    fn malformed_profiles_are_rejected() {
+        for spec in [
+            "", "constant", "constant:0", "constant:-1", "constant:x", "constant:1:2", "ramp:1:2",
+            "ramp:1:2:0", "step::10", "step:1,x:10", "step:1:0", "sine:0:1:10", "burst:0:1", "square:1",
+            "burst:1.5:2", "burst:-1:2", "burst:2:inf", "constant:inf", "constant:NaN", "ramp:1:inf:10", "step:1,inf:10",
+            "sine:NaN:1:10",
+        ] {
+            assert!(spec.parse::<LoadProfile>().is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn expected_messages_match_the_rate() {
+        let ramp = LoadProfile::Ramp { from: 0.0, to: 100.0, duration_s: 10.0 };
+        assert_eq!(ramp.rate_at(5.0), 50.0);
+        assert_eq!(ramp.rate_at(20.0), 100.0);
+        assert_eq!(ramp.expected_messages(10.0), 500.0);
+        assert_eq!(ramp.expected_messages(12.0), 700.0);
+
+        let step = LoadProfile::Step { rates: vec![10.0, 20.0], step_s: 5.0 };
+        assert_eq!(step.rate_at(7.0), 20.0);
+        assert_eq!(step.rate_at(100.0), 20.0);
+        assert_eq!(step.expected_messages(10.0), 150.0);
+
+        let sine = LoadProfile::Sinusoidal { mean: 10.0, amplitude: 20.0, period_s: 4.0 };
+        assert_eq!(sine.rate_at(3.0), 0.0);
+        assert!((sine.expected_messages(4.0) - 40.0).abs() < 1e-9);
+    }
+}
@@ -1,115 +1,409 @@
 use futures::future;
-use paho_mqtt::Message;
-use tokio::{sync::Mutex, time::sleep};
-use std::{sync::Arc, time::Duration, error::Error};
-use paho_mqtt::{AsyncClient, ConnectOptionsBuilder, CreateOptionsBuilder};
+use tokio::{sync::Mutex, time::{sleep, sleep_until, timeout, Instant}};
+use std::{collections::BTreeMap, sync::Arc, time::Duration, error::Error};
+use paho_mqtt::{AsyncClient, ConnectOptions, Message};
 
 use crate::log;
 use crate::write_log;
+use crate::connection::{ConnectionConfig, ConnectionEvents, MqttVersion, describe_error, error_kind};
+use crate::payload::{PayloadHeader, PayloadKind, build_message, generate_body, now_micros};
+use crate::stats::{Histogram, PublisherStats};
+use crate::profile::LoadProfile;
+use crate::fault::{FaultEvent, FaultSpec, FaultTarget, spawn_client_faults};
+
+// How many missed ticks a pacer may catch up on in one burst after a stall
+const PACER_MAX_BURST: u32 = 10;
+
+// Step by which a pacer advances while its profile asks for a zero or slow rate
+const PACER_IDLE_STEP: Duration = Duration::from_millis(10);
+
+// Longest a pacer sleeps without looking at the stop flag
+const PACER_STOP_CHECK: Duration = Duration::from_millis(100);
+
+// Longest wait for a publish to complete, a client that doesn't reconnect mustn't hold up the stop
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Schedules publishes on ticks following a load profile, so the time spent publishing
+// doesn't lower the rate
+pub struct Pacer {
+    profile: LoadProfile,
+    started_at: Instant,
+    next_tick: Instant,
+    // messages sent of the current burst, burst profiles only
+    n_in_burst: u64,
+    // messages owed while the rate is below one per idle step, starts at one so the first tick is immediate
+    credit: f64,
+}
+
+impl Pacer {
+    pub fn new(profile: LoadProfile) -> Self {
+        let now = Instant::now();
+        return Self { profile, started_at: now, next_tick: now, n_in_burst: 0, credit: 1.0 }
+    }
+
+    // Waits for the next tick, ticks missed for longer than the burst allowance are dropped.
+    // Returns false without ticking once `stop` is set
+    pub async fn tick(&mut self, stop: &Mutex<bool>) -> bool {
+        if let LoadProfile::Burst { size, every_s } = self.profile {
+            if self.n_in_burst >= size {
+                let every = Duration::from_secs_f64(every_s);
+                self.n_in_burst = 0;
+                self.next_tick += every;
+                // a burst that is already a full period late is skipped
+                if self.next_tick + every < Instant::now() {
+                    self.next_tick = Instant::now();
+                }
+            }
+            if !sleep_until_or_stop(self.next_tick, stop).await {
+                return false
+            }
+            self.n_in_burst += 1;
+            return true
+        }
+
+        loop {
+            let rate = self.profile.rate_at(self.next_tick.duration_since(self.started_at).as_secs_f64());
+            if rate * PACER_IDLE_STEP.as_secs_f64() >= 1.0 {
+                let interval = Duration::from_secs_f64(1.0 / rate);
+                if let Some(earliest) = Instant::now().checked_sub(interval * PACER_MAX_BURST) {
+                    if self.next_tick < earliest {
+                        self.next_tick = earliest;
+                    }
+                }
+                sleep_until(self.next_tick).await;
+                self.next_tick += interval;
+                self.credit = 0.0;
+                return true
+            }
+
+            // below one message per step the rate is integrated step by step, so a profile rising
+            // out of a slow phase isn't stuck on the long interval it started with
+            if self.credit >= 1.0 && rate > 0.0 {
+                sleep_until(self.next_tick).await;
+                self.credit -= 1.0;
+                return true
+            }
+            if *stop.lock().await {
+                return false
+            }
+            sleep_until(self.next_tick).await;
+            self.credit = (self.credit + rate * PACER_IDLE_STEP.as_secs_f64()).min(PACER_MAX_BURST as f64);
+            self.next_tick += PACER_IDLE_STEP;
+        }
+    }
+}
+
+// Sleeps until `deadline`, looking at `stop` every PACER_STOP_CHECK. False as soon as it is set
+async fn sleep_until_or_stop(deadline: Instant, stop: &Mutex<bool>) -> bool {
+    while deadline > Instant::now() + PACER_STOP_CHECK {
+        sleep(PACER_STOP_CHECK).await;
+        if *stop.lock().await {
+            return false
+        }
+    }
+    sleep_until(deadline).await;
+    return true
+}
+
+// How a publish ended after its retries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PublishOutcome {
+    Acked,
+    // still buffered or in flight, it may yet reach the broker
+    TimedOut,
+    // failed for good, it never reached the broker
+    Failed,
+}
+
+impl PublishOutcome {
+    // Sequence number of the next message. A failed publish hands its number on, so it isn't
+    // mistaken for broker loss in the subscribers' gap accounting
+    fn next_seq(self, seq: u64) -> u64 {
+        return if self == PublishOutcome::Failed { seq } else { seq + 1 }
+    }
+}
 
 pub struct Publisher {
     clients: Arc<Mutex<Vec<AsyncClient>>>,
+    events: Vec<Arc<ConnectionEvents>>,
+    // kept to connect the clients again after an injected fault
+    connect_options: ConnectOptions,
     pub make_graceful_stop: Arc<Mutex<bool>>,
-    delay: u64, qos: i32,
+    controller_id: String, first_index: usize,
+    delay: u64, qos: i32, retain: bool,
+    // MQTT v5 carries the sequence header in user properties instead of the payload
+    header_in_properties: bool,
+    payload_kind: PayloadKind, payload_size: usize,
+    profile: Option<LoadProfile>,
+    fault_specs: Vec<FaultSpec>,
+    // every fault injected into these clients so far
+    pub faults: Arc<Mutex<Vec<FaultEvent>>>,
+}
+
+// Client ids are namespaced per controller, the topic index is global across controllers
+pub fn publisher_client_id(controller_id: &str, index: usize) -> String {
+    return format!("publisher_{}_{}", controller_id, index)
+}
+
+pub fn publisher_topic(index: usize, qos: i32, delay: u64) -> String {
+    return format!("counter/{}/{}/{}", index, qos, delay)
 }
 
 impl Publisher {
     pub async fn connect(
-        host_uri: &str, creds: Option<(&str, &str)>,
-        n_instances: usize, delay: u64, qos: i32
+        connection: &ConnectionConfig,
+        controller_id: &str, first_index: usize,
+        n_instances: usize, delay: u64, qos: i32, retain: bool,
+        payload_kind: PayloadKind, payload_size: usize,
+        profile: Option<LoadProfile>, fault_specs: Vec<FaultSpec>
     ) -> Result<Self, String> {
         if n_instances > 0 {
+            let connect_options = connection.connect_options()?.finalize();
             let mut connect_all = Vec::new();
 
-            for i in 0..n_instances {
+            for i in first_index..(first_index + n_instances) {
+                let connect_options = connect_options.clone();
                 connect_all.push(async move {
                     let client = AsyncClient::new(
-                        CreateOptionsBuilder::new()
-                        .client_id(format!("publisher_{}", i))
-                        .server_uri(host_uri)
-                        .finalize()
-                    ).unwrap();
-                    if let Some((username, password)) = creds {
-                        client.connect(
-                            ConnectOptionsBuilder::new()
-                            .user_name(username)
-                            .password(password)
-                            .finalize()
-                        ).await.unwrap();
-                    } else {
-                        client.connect(
-                            ConnectOptionsBuilder::new().finalize()
-                        ).await.unwrap();
-                    }
-                    return client
+                        connection.create_options(&publisher_client_id(controller_id, i))
+                    ).map_err(|e| e.to_string())?;
+                    client.connect(connect_options).await.map_err(|e| describe_error(&e))?;
+                    return Ok::<AsyncClient, String>(client)
                 });
             }
     
+            let clients = future::try_join_all(connect_all).await?;
+            // watched from the start so faults injected before the first publish are counted
+            let events = clients.iter().map(|x| ConnectionEvents::watch(x, |_| {})).collect();
             return Ok(Self {
-                clients: Arc::new(Mutex::new(
-                    future::join_all(connect_all).await
-                )),
+                clients: Arc::new(Mutex::new(clients)),
+                events: events,
+                connect_options: connect_options,
                 make_graceful_stop: Arc::new(Mutex::new(false)),
+                controller_id: controller_id.to_string(),
+                first_index: first_index,
                 delay: delay,
                 qos: qos,
+                retain: retain,
+                header_in_properties: connection.mqtt_version == MqttVersion::V5,
+                payload_kind: payload_kind,
+                payload_size: payload_size,
+                profile: profile,
+                fault_specs: fault_specs,
+                faults: Arc::new(Mutex::new(Vec::new())),
             });
         } else {
             return Err("".to_string())
         }
     }
 
-    pub async fn start(&mut self) -> Vec<u64> {
+    pub async fn start(&mut self) -> Vec<PublisherStats> {
         let mut async_instances = Vec::new();
         for i in 0..self.clients.lock().await.len() {
             let clients = Arc::clone(&self.clients);
             let make_graceful_stop = Arc::clone(&self.make_graceful_stop);
             let delay = self.delay;
             let qos = self.qos;
+            let retain = self.retain;
+            let index = self.first_index + i;
+            let publisher_id = publisher_client_id(&self.controller_id, index);
+            let header_in_properties = self.header_in_properties;
+            let profile = self.profile.clone();
+            let events = Arc::clone(&self.events[i]);
+            // generated once per client, only the header changes from message to message
+            let body = generate_body(self.payload_kind, self.payload_size, index as u64 + 1);
 
             async_instances.push(async move {
+                let topic = publisher_topic(index, qos, delay);
                 let mut iter: u64 = 0;
+                let mut n_failed: u64 = 0;
+                let mut n_retries: u64 = 0;
+                let mut ack_latency = Histogram::new();
+                let mut errors: BTreeMap<String, u64> = BTreeMap::new();
+                let mut n_recovered: u64 = 0;
+                let mut last_error: Option<String> = None;
+                let mut pacer = profile.clone().map(Pacer::new);
+                // a publisher restarting with a fresh counter gets a newer epoch
+                let epoch = now_micros() as u64;
+                let started_at = Instant::now();
                 while !(*make_graceful_stop.lock().await) {
-                    // applying delay
-                    sleep(Duration::from_millis(delay)).await;
+                    // applying rate or delay
+                    match pacer.as_mut() {
+                        Some(pacer) => {
+                            if !pacer.tick(&make_graceful_stop).await { break; }
+                        }
+                        None => { sleep(Duration::from_millis(delay)).await; }
+                    }
 
                     let mut retries = 3;
-                    loop {
-                        match (*clients.lock().await)[i].publish(
-                            Message::new(
-                                format!("counter/{}/{}/{}", i, qos, delay), 
-                                iter.to_string(), 
-                                qos as i32
-                            )
-                        ).await {
-                            Ok(_) => { break; }
-                            Err(msg) => { 
-                                if retries == 0 { break; }
+                    let outcome = loop {
+                        // stamped on every attempt so retries don't inflate the measured latency
+                        let message = build_message(
+                            &topic,
+                            &PayloadHeader::new(iter, &publisher_id, epoch),
+                            &body,
+                            qos as i32,
+                            retain,
+                            header_in_properties
+                        );
+                        let n_disconnects = events.n_disconnects();
+                        // the lock is released before the handshake so clients don't wait on each other's acks
+                        // stamped before the call so the time spent queueing counts towards the latency
+                        let published_at = Instant::now();
+                        let token = {
+                            let clients = clients.lock().await;
+                            clients[i].publish(message)
+                        };
+                        let completed = match timeout(PUBLISH_TIMEOUT, token).await {
+                            Ok(completed) => completed,
+                            // still buffered or in flight, retrying would only duplicate it
+                            Err(_) => {
+                                *errors.entry("Timeout".to_string()).or_insert(0) += 1;
+                                last_error = Some(format!("not acknowledged within {}s", PUBLISH_TIMEOUT.as_secs()));
+                                break PublishOutcome::TimedOut;
+                            }
+                        };
+                        match completed {
+                            Ok(_) => {
+                                ack_latency.record(published_at.elapsed().as_micros() as u64);
+                                if qos > 0 && events.n_disconnects() != n_disconnects {
+                                    n_recovered += 1;
+                                }
+                                break PublishOutcome::Acked;
+                            }
+                            Err(msg) => {
+                                *errors.entry(error_kind(&msg)).or_insert(0) += 1;
+                                if retries == 0 {
+                                    n_failed += 1;
+                                    last_error = Some(describe_error(&msg));
+                                    break PublishOutcome::Failed;
+                                }
                                 retries -= 1;
+                                n_retries += 1;
                             }
                         }
-                    }
+                    };
 
-                    iter += 1;
+                    iter = outcome.next_seq(iter);
                 }
 
+                let elapsed = started_at.elapsed();
+                let stats = PublisherStats {
+                    client_index: index,
+                    // publishes that failed for good gave their sequence number to the next one
+                    n_messages_sent: iter,
+                    elapsed_ms: elapsed.as_millis() as u64,
+                    target_rate: profile.as_ref().map(|x| {
+                        x.expected_messages(elapsed.as_secs_f64()) / elapsed.as_secs_f64().max(f64::MIN_POSITIVE)
+                    }),
+                    ack_latency: ack_latency,
+                    n_failed: n_failed,
+                    n_retries: n_retries,
+                    errors: errors,
+                    connection: events.stats(n_recovered),
+                };
+
+                if retain {
+                    // an empty retained message clears the topic for the next iteration
+                    let _ = (*clients.lock().await)[i].publish(Message::new_retained(&topic, Vec::new(), qos)).await;
+                }
                 (*clients.lock().await)[i].disconnect(None).await;
                 
                 write_log!(r#"Publisher client {} terminated. [graceful stop=true] [
     total_n_messages_sent={},
+    n_failed_publishes={},
+    n_retries={},
+    errors={:?},
+    last_error={},
+    ack_latency_us=[{}],
+    n_disconnects={},
+    n_reconnects={},
+    downtime_ms={},
+    n_recovered={},
+    target_rate={},
+    achieved_rate={:.1},
     qos={},
 ]
-                "#, i, iter, qos);
+                "#, publisher_id, stats.n_messages_sent, n_failed, n_retries, stats.errors, last_error.unwrap_or("none".to_string()), stats.ack_latency.summary(),
+                stats.connection.n_disconnects, stats.connection.n_reconnects, stats.connection.downtime_ms, stats.connection.n_recovered,
+                stats.target_rate.map(|x| format!("{:.1}", x)).unwrap_or("NA".to_string()), stats.achieved_rate(), qos);
 
-                return iter;
+                return stats;
             });
         }
 
-        write_log!(r#"Publisher clients 0..{} ready. [
+        write_log!(r#"Publisher clients {}..{} ready. [
+    controller_id={},
     qos={},
     delay={},
+    retain={},
+    payload={}x{}B,
+    profile={},
 ]
-        "#, async_instances.len() - 1, self.qos, self.delay);
+        "#, self.first_index, self.first_index + async_instances.len() - 1, self.controller_id, self.qos, self.delay, self.retain,
+        self.payload_kind, self.payload_size, self.profile.as_ref().map(|x| x.to_string()).unwrap_or("none".to_string()));
 
-        return future::join_all(async_instances).await
+        let fault_tasks = spawn_client_faults(
+            &self.fault_specs, FaultTarget::Publishers, &self.clients, &self.events, self.first_index, &self.connect_options, &self.faults
+        );
+        let stats = future::join_all(async_instances).await;
+        for task in fault_tasks {
+            task.abort();
+        }
+        return stats
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_publishes_are_not_counted_as_loss() {
+        use crate::stats::{Arrival, SequenceTracker};
+        let outcomes = [
+            PublishOutcome::Acked, PublishOutcome::Failed, PublishOutcome::Failed, PublishOutcome::Acked,
+            PublishOutcome::TimedOut, PublishOutcome::Failed, PublishOutcome::Acked,
+        ];
+        let mut tracker = SequenceTracker::default();
+        let mut seq = 0;
+        for outcome in outcomes {
+            if outcome != PublishOutcome::Failed {
+                assert_eq!(tracker.record(seq, 1), Arrival::InOrder);
+            }
+            seq = outcome.next_seq(seq);
+        }
+        // sent count of the publisher
+        assert_eq!(seq, 4);
+        assert_eq!(tracker.n_missing(), 0);
+    }
+
+    #[tokio::test]
+    async fn slow_rates_still_publish() {
+        let stop = Mutex::new(false);
+        let mut pacer = Pacer::new(LoadProfile::Constant { rate: 0.05 });
+        assert!(timeout(Duration::from_secs(1), pacer.tick(&stop)).await.unwrap());
+
+        // a rate of 0.05 msg/s owes its next message 20s later
+        let mut pacer = Pacer::new(LoadProfile::Constant { rate: 0.05 });
+        pacer.tick(&stop).await;
+        pacer.next_tick -= Duration::from_secs(20);
+        assert!(timeout(Duration::from_secs(1), pacer.tick(&stop)).await.unwrap());
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn stop_interrupts_a_long_wait() {
+        let stop = Arc::new(Mutex::new(false));
+        let mut pacer = Pacer::new(LoadProfile::Burst { size: 1, every_s: 60.0 });
+        assert!(pacer.tick(&stop).await);
+
+        let stopper = Arc::clone(&stop);
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(50)).await;
+            *stopper.lock().await = true;
+        });
+        assert!(!timeout(Duration::from_secs(1), pacer.tick(&stop)).await.unwrap());
+
+        let mut pacer = Pacer::new(LoadProfile::Step { rates: vec![0.0], step_s: 1.0 });
+        assert!(!timeout(Duration::from_secs(1), pacer.tick(&stop)).await.unwrap());
+    }
+}
@@ -0,0 +1,393 @@
+use chrono::{DateTime, Utc};
+use path_clean::clean;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Write;
+
+use crate::broker::BrokerWindow;
+use crate::connection::{MqttVersion, SessionMode};
+use crate::payload::PayloadKind;
+use crate::stats::{ConnectionStats, FaultReport, HistogramSummary, PublisherStats, RunResult, SaturationResult, SharedSummary, SourceStats, SubscriberStats};
+use crate::stats::TimelineBucket;
+
+// One row per (delay, qos, instancecount, client) combination
+#[derive(Debug, Clone, Serialize)]
+pub struct ResultRow {
+    pub delay: u64,
+    pub qos: i32,
+    pub instancecount: usize,
+    pub tls: bool,
+    pub retain: bool,
+    pub session: SessionMode,
+    pub mqtt_version: MqttVersion,
+    pub fan_out: usize,
+    pub topology: String,
+    pub payload_size: usize,
+    pub payload_kind: PayloadKind,
+    pub profile: Option<String>,
+    // shared subscription group the client was a member of
+    pub shared_group: Option<String>,
+    pub client: usize,
+    pub n_messages: u64,
+    pub messages_per_second: u64,
+    pub n_bytes: u64,
+    pub n_lost: u64,
+    pub n_duplicates: u64,
+    pub n_out_of_order: u64,
+    pub n_reordered: u64,
+    pub n_late: u64,
+    pub n_beyond_window: u64,
+    pub reorder_distance: HistogramSummary,
+    pub n_restarts: u64,
+    pub n_sources: usize,
+    pub connection: ConnectionStats,
+    pub window_ms: u64,
+    pub first_message_at: Option<DateTime<Utc>>,
+    pub last_message_at: Option<DateTime<Utc>>,
+    pub latency_us: HistogramSummary,
+    pub inter_arrival_us: HistogramSummary,
+    // broker-wide deltas over the iteration, repeated on every client row
+    pub broker_messages_received: Option<f64>,
+    pub broker_messages_sent: Option<f64>,
+    // per publisher topic breakdown, JSON export only
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<SourceRow>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SourceRow {
+    pub topic: String,
+    pub n_messages: u64,
+    pub n_lost: u64,
+    pub n_duplicates: u64,
+    pub n_out_of_order: u64,
+    pub n_reordered: u64,
+    pub n_late: u64,
+    pub n_beyond_window: u64,
+    pub reorder_distance: HistogramSummary,
+    pub n_restarts: u64,
+    // as reported by the publisher, None if it didn't report
+    pub n_sent: Option<u64>,
+    pub latency_us: HistogramSummary,
+}
+
+impl SourceRow {
+    pub fn new(source: &SourceStats) -> Self {
+        return Self {
+            topic: source.topic.clone(),
+            n_messages: source.n_messages,
+            n_lost: source.n_lost,
+            n_duplicates: source.n_duplicates,
+            n_out_of_order: source.n_out_of_order,
+            n_reordered: source.n_reordered,
+            n_late: source.n_late,
+            n_beyond_window: source.n_beyond_window,
+            reorder_distance: source.reorder_distance.summary(),
+            n_restarts: source.n_restarts,
+            n_sent: source.n_sent,
+            latency_us: source.latency.summary(),
+        }
+    }
+}
+
+impl ResultRow {
+    pub fn new(result: &RunResult, client: &SubscriberStats) -> Self {
+        return Self {
+            delay: result.delay,
+            qos: result.qos,
+            instancecount: result.instancecount,
+            tls: result.tls,
+            retain: result.retain,
+            session: result.session,
+            mqtt_version: result.mqtt_version,
+            fan_out: result.fan_out,
+            topology: result.topology.clone(),
+            payload_size: result.payload_size,
+            payload_kind: result.payload_kind,
+            profile: result.profile.as_ref().map(|x| x.to_string()),
+            shared_group: result.shared.as_ref().map(|x| x.group.clone()),
+            client: client.client_index,
+            n_messages: client.n_messages,
+            messages_per_second: client.messages_per_second,
+            n_bytes: client.n_bytes,
+            n_lost: client.n_lost,
+            n_duplicates: client.n_duplicates,
+            n_out_of_order: client.n_out_of_order,
+            n_reordered: client.n_reordered,
+            n_late: client.n_late,
+            n_beyond_window: client.n_beyond_window,
+            reorder_distance: client.reorder_distance.summary(),
+            n_restarts: client.n_restarts,
+            n_sources: client.n_sources,
+            connection: client.connection,
+            window_ms: client.window_ms(),
+            first_message_at: client.first_message_at,
+            last_message_at: client.last_message_at,
+            latency_us: client.latency.summary(),
+            inter_arrival_us: client.inter_arrival.summary(),
+            broker_messages_received: result.broker.and_then(|x| x.messages_received()),
+            broker_messages_sent: result.broker.and_then(|x| x.messages_sent()),
+            // a single source is the client itself
+            sources: if client.sources.len() > 1 { client.sources.iter().map(SourceRow::new).collect() } else { Vec::new() },
+        }
+    }
+}
+
+// Aggregated view of one sweep iteration
+#[derive(Debug, Clone, Serialize)]
+pub struct RunReport {
+    pub delay: u64,
+    pub qos: i32,
+    pub instancecount: usize,
+    pub tls: bool,
+    pub retain: bool,
+    pub session: SessionMode,
+    pub mqtt_version: MqttVersion,
+    pub fan_out: usize,
+    pub topology: String,
+    pub payload_size: usize,
+    pub payload_kind: PayloadKind,
+    pub profile: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: DateTime<Utc>,
+    pub n_messages: u64,
+    pub messages_per_second: u64,
+    pub n_bytes: u64,
+    pub n_lost: u64,
+    pub n_duplicates: u64,
+    pub n_reordered: u64,
+    pub n_late: u64,
+    pub reorder_distance: HistogramSummary,
+    pub n_messages_sent: Option<u64>,
+    // sums over the publisher clients
+    pub target_rate: Option<f64>,
+    pub achieved_rate: Option<f64>,
+    pub latency_us: HistogramSummary,
+    // publish to acknowledgement, over all publisher clients
+    pub ack_latency_us: HistogramSummary,
+    pub n_failed_publishes: u64,
+    // summed over the clients of each side
+    pub subscriber_connection: ConnectionStats,
+    pub publisher_connection: ConnectionStats,
+    pub broker: Option<BrokerWindow>,
+    pub shared: Option<SharedSummary>,
+    // delivery around every injected fault
+    pub faults: Vec<FaultReport>,
+    pub publishers: Vec<PublisherRow>,
+    // all subscriber clients merged, per bucket of send time
+    pub timeline: Vec<TimelineRow>,
+    pub clients: Vec<ResultRow>,
+}
+
+impl RunReport {
+    pub fn new(result: &RunResult) -> Self {
+        return Self {
+            delay: result.delay,
+            qos: result.qos,
+            instancecount: result.instancecount,
+            tls: result.tls,
+            retain: result.retain,
+            session: result.session,
+            mqtt_version: result.mqtt_version,
+            fan_out: result.fan_out,
+            topology: result.topology.clone(),
+            payload_size: result.payload_size,
+            payload_kind: result.payload_kind,
+            profile: result.profile.as_ref().map(|x| x.to_string()),
+            started_at: result.started_at,
+            ended_at: result.ended_at,
+            n_messages: result.n_messages(),
+            messages_per_second: result.messages_per_second(),
+            n_bytes: result.n_bytes(),
+            n_lost: result.n_lost(),
+            n_duplicates: result.n_duplicates(),
+            n_reordered: result.n_reordered(),
+            n_late: result.n_late(),
+            reorder_distance: result.reorder_distance().summary(),
+            n_messages_sent: result.n_messages_sent(),
+            target_rate: result.target_rate(),
+            achieved_rate: if result.publishers.is_empty() { None } else { Some(result.achieved_rate()) },
+            latency_us: result.latency().summary(),
+            ack_latency_us: result.ack_latency().summary(),
+            n_failed_publishes: result.n_failed_publishes(),
+            subscriber_connection: result.subscriber_connection(),
+            publisher_connection: result.publisher_connection(),
+            broker: result.broker,
+            shared: result.shared.clone(),
+            faults: result.faults.clone(),
+            publishers: result.publishers.iter().map(PublisherRow::new).collect(),
+            timeline: {
+                let timeline = result.timeline();
+                let n_publishers = result.publishers.len().max(1) as f64;
+                timeline.buckets.iter().enumerate().map(|(i, bucket)| {
+                    let offset_ms = i as u64 * timeline.bucket_ms;
+                    let target_rate = result.profile.as_ref().map(|x| x.rate_at(offset_ms as f64 / 1000.0) * n_publishers);
+                    TimelineRow::new(offset_ms, target_rate, bucket)
+                }).collect()
+            },
+            clients: result.clients.iter().map(|x| ResultRow::new(result, x)).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TimelineRow {
+    pub offset_ms: u64,
+    // summed over the publisher clients, None in delay mode
+    pub target_rate: Option<f64>,
+    pub n_messages: u64,
+    pub n_lost: u64,
+    pub n_duplicates: u64,
+    pub n_recovered: u64,
+    pub latency_mean_us: u64,
+    pub latency_max_us: u64,
+}
+
+impl TimelineRow {
+    pub fn new(offset_ms: u64, target_rate: Option<f64>, bucket: &TimelineBucket) -> Self {
+        return Self {
+            offset_ms,
+            target_rate,
+            n_messages: bucket.n_messages,
+            n_lost: bucket.n_lost(),
+            n_duplicates: bucket.n_duplicates,
+            n_recovered: bucket.n_recovered,
+            latency_mean_us: bucket.latency_mean_us(),
+            latency_max_us: bucket.latency_max_us,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PublisherRow {
+    pub client: usize,
+    pub n_messages_sent: u64,
+    pub elapsed_ms: u64,
+    pub target_rate: Option<f64>,
+    pub achieved_rate: f64,
+    pub ack_latency_us: HistogramSummary,
+    pub n_failed: u64,
+    pub n_retries: u64,
+    pub errors: BTreeMap<String, u64>,
+    pub connection: ConnectionStats,
+}
+
+impl PublisherRow {
+    pub fn new(publisher: &PublisherStats) -> Self {
+        return Self {
+            client: publisher.client_index,
+            n_messages_sent: publisher.n_messages_sent,
+            elapsed_ms: publisher.elapsed_ms,
+            target_rate: publisher.target_rate,
+            achieved_rate: publisher.achieved_rate(),
+            ack_latency_us: publisher.ack_latency.summary(),
+            n_failed: publisher.n_failed,
+            n_retries: publisher.n_retries,
+            errors: publisher.errors.clone(),
+            connection: publisher.connection,
+        }
+    }
+}
+
+// Describes the sweep the results were measured with
+#[derive(Debug, Clone, Serialize)]
+pub struct RunMetadata {
+    pub tool_version: String,
+    pub host_uri: String,
+    pub tls_host_uri: Option<String>,
+    // sweep plan file, if the iterations came from one
+    pub plan: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub measuring_runtime_s: u64,
+    pub reset_buffer_s: u64,
+    pub delay_level_min: u64,
+    pub delay_level_max: u64,
+    pub instancecount_min: usize,
+    pub instancecount_max: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub metadata: RunMetadata,
+    pub runs: Vec<RunReport>,
+    // knee of every saturation search, the probes themselves are in `runs`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub saturation: Vec<SaturationResult>,
+}
+
+impl Report {
+    pub fn new(metadata: RunMetadata, results: &[RunResult], saturation: &[SaturationResult]) -> Self {
+        return Self {
+            metadata,
+            runs: results.iter().map(RunReport::new).collect(),
+            saturation: saturation.to_vec(),
+        }
+    }
+
+    pub fn rows(&self) -> impl Iterator<Item = &ResultRow> {
+        return self.runs.iter().flat_map(|x| x.clients.iter())
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from(
+            "delay,qos,instancecount,tls,retain,session,mqtt_version,fan_out,topology,payload_kind,payload_size,profile,shared_group,client,n_messages,messages_per_second,n_bytes,n_lost,n_duplicates,\
+            n_out_of_order,n_reordered,n_late,n_beyond_window,reorder_distance_max,n_restarts,n_sources,n_disconnects,n_reconnects,downtime_ms,n_recovered,window_ms,first_message_at,last_message_at,\
+            latency_mean_us,latency_p50_us,latency_p90_us,latency_p99_us,latency_p999_us,latency_max_us,\
+            inter_arrival_mean_us,inter_arrival_p50_us,inter_arrival_p99_us,inter_arrival_max_us,\
+            broker_messages_received,broker_messages_sent\n"
+        );
+        for row in self.rows() {
+            csv += &format!(
+                "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}\n",
+                row.delay, row.qos, row.instancecount, row.tls, row.retain, row.session, row.mqtt_version, row.fan_out, csv_field(&row.topology), row.payload_kind, row.payload_size,
+                // step profiles and group names may contain commas
+                row.profile.as_ref().map(|x| csv_field(&x.to_string())).unwrap_or_default(),
+                row.shared_group.as_ref().map(|x| csv_field(x)).unwrap_or_default(), row.client,
+                row.n_messages, row.messages_per_second, row.n_bytes, row.n_lost, row.n_duplicates,
+                row.n_out_of_order, row.n_reordered, row.n_late, row.n_beyond_window, row.reorder_distance.max, row.n_restarts, row.n_sources,
+                row.connection.n_disconnects, row.connection.n_reconnects, row.connection.downtime_ms, row.connection.n_recovered, row.window_ms,
+                row.first_message_at.map(|x| x.to_rfc3339()).unwrap_or_default(),
+                row.last_message_at.map(|x| x.to_rfc3339()).unwrap_or_default(),
+                row.latency_us.mean, row.latency_us.p50, row.latency_us.p90,
+                row.latency_us.p99, row.latency_us.p999, row.latency_us.max,
+                row.inter_arrival_us.mean, row.inter_arrival_us.p50,
+                row.inter_arrival_us.p99, row.inter_arrival_us.max,
+                row.broker_messages_received.map(|x| x.to_string()).unwrap_or_default(),
+                row.broker_messages_sent.map(|x| x.to_string()).unwrap_or_default()
+            );
+        }
+        return csv
+    }
+
+    pub fn to_json(&self) -> Result<String, String> {
+        return serde_json::to_string_pretty(self).map_err(|e| e.to_string())
+    }
+}
+
+// Quoted CSV field, embedded quotes are doubled
+fn csv_field(value: &str) -> String {
+    return format!("\"{}\"", value.replace('"', "\"\""))
+}
+
+// Writes `content` to `<dir_prefix>/<file_name>`, returning the full path
+pub fn write_export(dir_prefix: &str, file_name: &str, content: &str) -> Result<String, String> {
+    let full_path = clean(format!("{}/{}", dir_prefix, file_name));
+    fs::create_dir_all(dir_prefix).map_err(|e| e.to_string())?;
+    let mut file = File::create(&full_path).map_err(|e| e.to_string())?;
+    file.write_all(content.as_bytes()).map_err(|e| e.to_string())?;
+    return Ok(full_path.display().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_fields_are_quoted_and_escaped() {
+        assert_eq!(csv_field("shared(a,b)"), "\"shared(a,b)\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field(""), "\"\"");
+    }
+}
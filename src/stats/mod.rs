@@ -0,0 +1,1097 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::broker::{BrokerWindow, format_metric};
+use crate::connection::{MqttVersion, SessionMode};
+use crate::fault::FaultEvent;
+use crate::payload::PayloadKind;
+use crate::profile::LoadProfile;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+
+// Values below 2^SUB_BUCKET_BITS are recorded exactly, above that every power of two
+// range is split into SUB_BUCKET_HALF linear buckets (~1.5% worst case relative error)
+const SUB_BUCKET_BITS: u32 = 7;
+const SUB_BUCKET_COUNT: usize = 1 << SUB_BUCKET_BITS;
+const SUB_BUCKET_HALF: usize = SUB_BUCKET_COUNT / 2;
+const N_BUCKETS: usize = SUB_BUCKET_COUNT + (64 - SUB_BUCKET_BITS as usize) * SUB_BUCKET_HALF;
+
+// HDR style log-linear histogram with a fixed memory footprint, values are
+// expected in microseconds but any u64 is accepted
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(into = "SparseHistogram", try_from = "SparseHistogram")]
+pub struct Histogram {
+    counts: Vec<u64>,
+    total: u64,
+    sum: u128,
+    min: u64,
+    max: u64,
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        return Self {
+            counts: vec![0; N_BUCKETS],
+            total: 0,
+            sum: 0,
+            min: u64::MAX,
+            max: 0,
+        }
+    }
+
+    pub fn record(&mut self, value: u64) {
+        self.counts[bucket_index(value)] += 1;
+        self.total += 1;
+        self.sum += value as u128;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    pub fn merge(&mut self, other: &Histogram) {
+        for (count, other_count) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *count += other_count;
+        }
+        self.total += other.total;
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    pub fn len(&self) -> u64 { return self.total }
+
+    pub fn is_empty(&self) -> bool { return self.total == 0 }
+
+    pub fn min(&self) -> u64 { return if self.is_empty() { 0 } else { self.min } }
+
+    pub fn max(&self) -> u64 { return self.max }
+
+    pub fn mean(&self) -> u64 {
+        return if self.is_empty() { 0 } else { (self.sum / self.total as u128) as u64 }
+    }
+
+    // Highest value equivalent to the bucket holding the given percentile (0..=100)
+    pub fn value_at_percentile(&self, percentile: f64) -> u64 {
+        if self.is_empty() { return 0; }
+
+        let rank = ((percentile.clamp(0.0, 100.0) / 100.0) * self.total as f64).ceil().max(1.0) as u64;
+        let mut seen: u64 = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return bucket_upper_bound(index).min(self.max).max(self.min)
+            }
+        }
+        return self.max
+    }
+
+    pub fn summary(&self) -> HistogramSummary {
+        return HistogramSummary {
+            count: self.len(),
+            mean: self.mean(),
+            p50: self.value_at_percentile(50.0),
+            p90: self.value_at_percentile(90.0),
+            p99: self.value_at_percentile(99.0),
+            p999: self.value_at_percentile(99.9),
+            max: self.max(),
+        }
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self { return Self::new() }
+}
+
+// Serialized form of a histogram, only the non-empty buckets as (bucket, count) pairs so
+// control messages and state files don't carry thousands of zeros per histogram
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SparseHistogram {
+    counts: Vec<(usize, u64)>,
+    total: u64,
+    sum: u128,
+    min: u64,
+    max: u64,
+}
+
+impl From<Histogram> for SparseHistogram {
+    fn from(histogram: Histogram) -> Self {
+        return Self {
+            counts: histogram.counts.iter().enumerate().filter(|x| *x.1 != 0).map(|(i, &count)| (i, count)).collect(),
+            total: histogram.total,
+            sum: histogram.sum,
+            min: histogram.min,
+            max: histogram.max,
+        }
+    }
+}
+
+impl TryFrom<SparseHistogram> for Histogram {
+    type Error = String;
+
+    fn try_from(sparse: SparseHistogram) -> Result<Self, Self::Error> {
+        let mut counts = vec![0; N_BUCKETS];
+        for (index, count) in sparse.counts {
+            *counts.get_mut(index).ok_or(format!("histogram bucket {} out of range", index))? = count;
+        }
+        if counts.iter().sum::<u64>() != sparse.total {
+            return Err(format!("histogram bucket counts don't add up to its total of {}", sparse.total))
+        }
+        return Ok(Self { counts, total: sparse.total, sum: sparse.sum, min: sparse.min, max: sparse.max })
+    }
+}
+
+fn bucket_index(value: u64) -> usize {
+    if value < SUB_BUCKET_COUNT as u64 {
+        return value as usize
+    }
+    let magnitude = 63 - value.leading_zeros();
+    let shift = magnitude - (SUB_BUCKET_BITS - 1);
+    let sub_index = (value >> shift) as usize - SUB_BUCKET_HALF;
+    return SUB_BUCKET_COUNT + (shift as usize - 1) * SUB_BUCKET_HALF + sub_index
+}
+
+fn bucket_upper_bound(index: usize) -> u64 {
+    if index < SUB_BUCKET_COUNT {
+        return index as u64
+    }
+    let offset = index - SUB_BUCKET_COUNT;
+    let shift = (offset / SUB_BUCKET_HALF + 1) as u32;
+    let sub_index = (offset % SUB_BUCKET_HALF + SUB_BUCKET_HALF) as u64;
+    return ((((sub_index + 1) as u128) << shift) - 1).min(u64::MAX as u128) as u64
+}
+
+// Percentile snapshot of a histogram, all values share the histogram's unit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct HistogramSummary {
+    pub count: u64,
+    pub mean: u64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub p999: u64,
+    pub max: u64,
+}
+
+impl fmt::Display for HistogramSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.count == 0 {
+            return write!(f, "NA")
+        }
+        return write!(
+            f, "count={} mean={} p50={} p90={} p99={} p99.9={} max={}",
+            self.count, self.mean, self.p50, self.p90, self.p99, self.p999, self.max
+        )
+    }
+}
+
+// Connection losses of one client over a run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectionStats {
+    pub n_disconnects: u64,
+    pub n_reconnects: u64,
+    // time spent disconnected, including an outage still ongoing at the end
+    pub downtime_ms: u64,
+    // QoS 1/2 messages that made it across an outage: publishes acknowledged after the reconnect
+    // for publishers, messages sent before the reconnect and received after it for subscribers
+    pub n_recovered: u64,
+}
+
+impl ConnectionStats {
+    pub fn merge(&mut self, other: &ConnectionStats) {
+        self.n_disconnects += other.n_disconnects;
+        self.n_reconnects += other.n_reconnects;
+        self.downtime_ms += other.downtime_ms;
+        self.n_recovered += other.n_recovered;
+    }
+}
+
+// Statistics of a single publisher client over one run, reported back by the controller
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PublisherStats {
+    // global index, i.e. the index in the publisher's topic
+    pub client_index: usize,
+    // publishes that didn't fail for good, i.e. the sequence numbers used. Failed ones are counted in
+    // n_failed instead, timed out ones may still have reached the broker and count as sent
+    pub n_messages_sent: u64,
+    // time from the first publish to the stop
+    pub elapsed_ms: u64,
+    // mean messages per second the client's load profile asked for, None when it used a fixed delay
+    pub target_rate: Option<f64>,
+    // publish call to completion of the handshake (us): written out at QoS 0, PUBACK at QoS 1, PUBCOMP at QoS 2
+    #[serde(default)]
+    pub ack_latency: Histogram,
+    // publishes that still failed after the last retry
+    #[serde(default)]
+    pub n_failed: u64,
+    #[serde(default)]
+    pub n_retries: u64,
+    // failed attempts per error kind, retried ones included
+    #[serde(default)]
+    pub errors: BTreeMap<String, u64>,
+    #[serde(default)]
+    pub connection: ConnectionStats,
+}
+
+impl PublisherStats {
+    pub fn achieved_rate(&self) -> f64 {
+        if self.elapsed_ms == 0 { return 0.0 }
+        return self.n_messages_sent as f64 * 1000.0 / self.elapsed_ms as f64
+    }
+}
+
+// Share of a subscriber client's statistics coming from one publisher topic
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SourceStats {
+    pub topic: String,
+    pub latency: Histogram,
+    // distinct messages delivered, duplicates excluded
+    pub n_messages: u64,
+    // sent but never delivered, including the tail after the last delivery once reconciled
+    pub n_lost: u64,
+    pub n_duplicates: u64,
+    pub n_out_of_order: u64,
+    // filled a gap within the reorder window
+    pub n_reordered: u64,
+    // filled a gap beyond the reorder window
+    pub n_late: u64,
+    // arrived from behind the tracked window, a late delivery or a duplicate, nobody can tell
+    #[serde(default)]
+    pub n_beyond_window: u64,
+    // sequence numbers between a late arrival and the highest one received before it
+    pub reorder_distance: Histogram,
+    // times the sequence started over, e.g. a publisher reconnecting with a fresh counter
+    #[serde(default)]
+    pub n_restarts: u64,
+    // messages the publisher reported sending on this topic, None until reconciled
+    pub n_sent: Option<u64>,
+}
+
+impl SourceStats {
+    pub fn new(topic: &str) -> Self {
+        return Self {
+            topic: topic.to_string(),
+            latency: Histogram::new(),
+            n_messages: 0,
+            n_lost: 0,
+            n_duplicates: 0,
+            n_out_of_order: 0,
+            n_reordered: 0,
+            n_late: 0,
+            n_beyond_window: 0,
+            reorder_distance: Histogram::new(),
+            n_restarts: 0,
+            n_sent: None,
+        }
+    }
+
+    // Counts an arrival from this topic, late ones are reordered within `reorder_window`
+    pub fn record(&mut self, arrival: Arrival, reorder_window: u64) {
+        match arrival {
+            Arrival::InOrder => { self.n_messages += 1; }
+            Arrival::Gap { .. } => {
+                self.n_messages += 1;
+                self.n_out_of_order += 1;
+            }
+            Arrival::Late { distance } => {
+                self.n_messages += 1;
+                self.reorder_distance.record(distance);
+                if distance <= reorder_window { self.n_reordered += 1; } else { self.n_late += 1; }
+            }
+            Arrival::Duplicate => { self.n_duplicates += 1; }
+            // counted as neither delivered nor duplicate, the sequence number stays missing
+            Arrival::BeyondWindow => { self.n_beyond_window += 1; }
+            Arrival::Restart => {
+                self.n_messages += 1;
+                self.n_restarts += 1;
+            }
+            // delivered after the publisher had already started over
+            Arrival::Stale => {
+                self.n_messages += 1;
+                self.n_late += 1;
+            }
+        }
+    }
+}
+
+// Statistics gathered by a single subscriber client over one measuring window
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubscriberStats {
+    pub client_index: usize,
+    pub qos: i32,
+    pub delay: u64,
+    // one-way publish to receive latency (us)
+    pub latency: Histogram,
+    // gap between two in-order arrivals (us)
+    pub inter_arrival: Histogram,
+    pub n_messages: u64,
+    pub messages_per_second: u64,
+    // received body bytes, the sequence header excluded whichever way it travels, duplicates excluded
+    pub n_bytes: u64,
+    pub n_lost: u64,
+    pub n_duplicates: u64,
+    // number of forward jumps in the sequence
+    pub n_out_of_order: u64,
+    // late arrivals that filled a previously detected gap within the reorder window
+    pub n_reordered: u64,
+    // late arrivals beyond the reorder window
+    pub n_late: u64,
+    // arrivals from behind the tracked window, neither delivered nor duplicate
+    #[serde(default)]
+    pub n_beyond_window: u64,
+    pub reorder_distance: Histogram,
+    #[serde(default)]
+    pub n_restarts: u64,
+    #[serde(default)]
+    pub connection: ConnectionStats,
+    // number of distinct publisher streams received
+    pub n_sources: usize,
+    // per publisher topic breakdown, sorted by topic
+    pub sources: Vec<SourceStats>,
+    pub timeline: Timeline,
+    pub first_message_at: Option<DateTime<Utc>>,
+    pub last_message_at: Option<DateTime<Utc>>,
+}
+
+impl SubscriberStats {
+    pub fn new(client_index: usize, qos: i32, delay: u64) -> Self {
+        return Self {
+            client_index, qos, delay,
+            latency: Histogram::new(),
+            inter_arrival: Histogram::new(),
+            n_messages: 0,
+            messages_per_second: 0,
+            n_bytes: 0,
+            n_lost: 0,
+            n_duplicates: 0,
+            n_out_of_order: 0,
+            n_reordered: 0,
+            n_late: 0,
+            n_beyond_window: 0,
+            reorder_distance: Histogram::new(),
+            n_restarts: 0,
+            connection: ConnectionStats::default(),
+            n_sources: 0,
+            sources: Vec::new(),
+            timeline: Timeline::default(),
+            first_message_at: None,
+            last_message_at: None,
+        }
+    }
+
+    // Time between the first and the last received message
+    pub fn window_ms(&self) -> u64 {
+        if let (Some(first), Some(last)) = (self.first_message_at, self.last_message_at) {
+            return last.signed_duration_since(first).num_milliseconds().max(0) as u64
+        }
+        return 0
+    }
+
+    // Recomputes the sequence counters from the per topic breakdown
+    pub fn sum_sources(&mut self) {
+        self.n_sources = self.sources.len();
+        self.n_messages = self.sources.iter().map(|x| x.n_messages).sum();
+        self.n_lost = self.sources.iter().map(|x| x.n_lost).sum();
+        self.n_duplicates = self.sources.iter().map(|x| x.n_duplicates).sum();
+        self.n_out_of_order = self.sources.iter().map(|x| x.n_out_of_order).sum();
+        self.n_reordered = self.sources.iter().map(|x| x.n_reordered).sum();
+        self.n_late = self.sources.iter().map(|x| x.n_late).sum();
+        self.n_beyond_window = self.sources.iter().map(|x| x.n_beyond_window).sum();
+        self.n_restarts = self.sources.iter().map(|x| x.n_restarts).sum();
+        self.reorder_distance = Histogram::new();
+        for source in self.sources.iter() {
+            self.reorder_distance.merge(&source.reorder_distance);
+        }
+    }
+}
+
+// How an arriving sequence number relates to the ones seen before on its stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arrival {
+    InOrder,
+    // jumped ahead, the skipped sequence numbers are now missing
+    Gap { skipped: u64 },
+    // filled a previously detected gap, `distance` sequence numbers behind the highest one received
+    Late { distance: u64 },
+    Duplicate,
+    // from behind the tracked window, where received and missing sequence numbers are forgotten
+    BeyondWindow,
+    // first message of a newer epoch, the publisher started over with a fresh counter
+    Restart,
+    // from an epoch the publisher has since left behind, it can't fill any gap anymore
+    Stale,
+}
+
+// Sequence numbers remembered behind the highest one received, 8 KiB per stream. Missing ones
+// sliding out of the window stay counted as lost
+const SEQUENCE_WINDOW: u64 = 1 << 16;
+// Sequence numbers less than this ahead (modulo 2^64) are ahead, the others behind
+const HALF_SEQUENCE_SPACE: u64 = 1 << 63;
+
+// Sequence state of one publisher stream as seen by one subscriber client, in constant memory
+#[derive(Debug, Clone)]
+pub struct SequenceTracker {
+    // counter run of the publisher, the stream only restarts when it changes
+    epoch: Option<u64>,
+    next: u64,
+    // sequence numbers behind `next` the bitmap holds, reset when the stream restarts
+    n_tracked: u64,
+    // one bit per sequence number modulo the window, set once received
+    received: Vec<u64>,
+    // skipped sequence numbers that haven't arrived (yet), including those out of the window
+    n_missing: u64,
+}
+
+impl Default for SequenceTracker {
+    fn default() -> Self {
+        return Self { epoch: None, next: 0, n_tracked: 0, received: vec![0; (SEQUENCE_WINDOW / 64) as usize], n_missing: 0 }
+    }
+}
+
+impl SequenceTracker {
+    // `epoch` identifies the publisher's counter, e.g. the time it started counting
+    pub fn record(&mut self, seq: u64, epoch: u64) -> Arrival {
+        match self.epoch {
+            Some(current) if epoch < current => { return Arrival::Stale }
+            Some(current) if epoch > current => {
+                // earlier gaps of the stream stay counted as lost, the new counter starts at 0
+                self.epoch = Some(epoch);
+                self.next = 0;
+                self.n_tracked = 0;
+                self.record_seq(seq);
+                return Arrival::Restart
+            }
+            Some(_) => { return self.record_seq(seq) }
+            None => {
+                self.epoch = Some(epoch);
+                return self.record_seq(seq)
+            }
+        }
+    }
+
+    pub fn n_missing(&self) -> u64 {
+        return self.n_missing
+    }
+
+    fn record_seq(&mut self, seq: u64) -> Arrival {
+        // wrapping, a counter overflowing back to 0 still reads as in order
+        let ahead = seq.wrapping_sub(self.next);
+        if ahead == 0 {
+            self.advance(seq, 0);
+            return Arrival::InOrder
+        }
+        if ahead < HALF_SEQUENCE_SPACE {
+            self.advance(seq, ahead);
+            self.n_missing = self.n_missing.saturating_add(ahead);
+            return Arrival::Gap { skipped: ahead }
+        }
+        let behind = self.next.wrapping_sub(seq);
+        if behind <= self.n_tracked {
+            if self.is_received(seq) {
+                return Arrival::Duplicate
+            }
+            self.set_received(seq, true);
+            self.n_missing -= 1;
+            return Arrival::Late { distance: behind - 1 }
+        }
+        // behind the window, a gap that slid out of it or a redelivery of a message received long ago.
+        // The window stays where it is, moving it back would count everything in between as missing
+        return Arrival::BeyondWindow
+    }
+
+    // Moves the window past `seq`, the `skipped` sequence numbers before it weren't received
+    fn advance(&mut self, seq: u64, skipped: u64) {
+        for offset in 1..=skipped.min(SEQUENCE_WINDOW - 1) {
+            self.set_received(seq.wrapping_sub(offset), false);
+        }
+        self.set_received(seq, true);
+        self.next = seq.wrapping_add(1);
+        self.n_tracked = (self.n_tracked + skipped + 1).min(SEQUENCE_WINDOW);
+    }
+
+    fn is_received(&self, seq: u64) -> bool {
+        let bit = seq % SEQUENCE_WINDOW;
+        return self.received[(bit / 64) as usize] & (1 << (bit % 64)) != 0
+    }
+
+    fn set_received(&mut self, seq: u64, received: bool) {
+        let bit = seq % SEQUENCE_WINDOW;
+        let word = &mut self.received[(bit / 64) as usize];
+        if received { *word |= 1 << (bit % 64); } else { *word &= !(1 << (bit % 64)); }
+    }
+}
+
+// Timeline buckets beyond this are dropped, guards against bogus send timestamps
+const MAX_TIMELINE_BUCKETS: usize = 100_000;
+
+// Deliveries within one timeline bucket, by publisher send time
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimelineBucket {
+    pub n_messages: u64,
+    // sequence numbers skipped by gaps detected in this bucket
+    pub n_skipped: u64,
+    // messages that filled a gap after all
+    pub n_late: u64,
+    #[serde(default)]
+    pub n_duplicates: u64,
+    // sent before the subscriber reconnected and received after
+    #[serde(default)]
+    pub n_recovered: u64,
+    pub latency_sum_us: u64,
+    pub latency_max_us: u64,
+}
+
+impl TimelineBucket {
+    pub fn add(&mut self, other: &TimelineBucket) {
+        self.n_messages += other.n_messages;
+        self.n_skipped += other.n_skipped;
+        self.n_late += other.n_late;
+        self.n_duplicates += other.n_duplicates;
+        self.n_recovered += other.n_recovered;
+        self.latency_sum_us += other.latency_sum_us;
+        self.latency_max_us = self.latency_max_us.max(other.latency_max_us);
+    }
+
+    pub fn n_lost(&self) -> u64 {
+        return self.n_skipped.saturating_sub(self.n_late)
+    }
+
+    pub fn latency_mean_us(&self) -> u64 {
+        if self.n_messages == 0 { return 0 }
+        return self.latency_sum_us / self.n_messages
+    }
+}
+
+// How delivery evolves over a run, in buckets of `bucket_ms` of send time counted
+// from the first message received
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Timeline {
+    pub bucket_ms: u64,
+    origin_us: Option<i64>,
+    pub buckets: Vec<TimelineBucket>,
+}
+
+impl Timeline {
+    pub fn new(bucket_ms: u64) -> Self {
+        return Self { bucket_ms: bucket_ms.max(1), origin_us: None, buckets: Vec::new() }
+    }
+
+    pub fn record(&mut self, sent_at_us: i64, latency_us: u64, arrival: Arrival, recovered: bool) {
+        let origin_us = *self.origin_us.get_or_insert(sent_at_us);
+        let index = ((sent_at_us - origin_us).max(0) as u64 / (self.bucket_ms * 1000)) as usize;
+        if index >= MAX_TIMELINE_BUCKETS { return; }
+        if self.buckets.len() <= index {
+            self.buckets.resize(index + 1, TimelineBucket::default());
+        }
+        let bucket = &mut self.buckets[index];
+        if arrival == Arrival::Duplicate {
+            bucket.n_duplicates += 1;
+            return
+        }
+        // may or may not be a duplicate, left out of the delivery counts
+        if arrival == Arrival::BeyondWindow {
+            return
+        }
+        bucket.n_messages += 1;
+        if recovered { bucket.n_recovered += 1; }
+        bucket.latency_sum_us += latency_us;
+        bucket.latency_max_us = bucket.latency_max_us.max(latency_us);
+        match arrival {
+            Arrival::Gap { skipped } => { bucket.n_skipped += skipped; }
+            Arrival::Late { .. } => { bucket.n_late += 1; }
+            _ => {}
+        }
+    }
+
+    // Adds up `other` bucket by bucket, both timelines start at their own first message
+    pub fn merge(&mut self, other: &Timeline) {
+        if self.buckets.len() < other.buckets.len() {
+            self.buckets.resize(other.buckets.len(), TimelineBucket::default());
+        }
+        for (bucket, other) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            bucket.add(other);
+        }
+    }
+
+    // Sum of the buckets overlapping [from_us, to_us) of send time
+    pub fn window(&self, from_us: i64, to_us: i64) -> TimelineBucket {
+        let mut total = TimelineBucket::default();
+        let Some(origin_us) = self.origin_us else { return total };
+        let bucket_us = (self.bucket_ms * 1000) as i64;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            let start_us = origin_us + i as i64 * bucket_us;
+            if start_us + bucket_us > from_us && start_us < to_us {
+                total.add(bucket);
+            }
+        }
+        return total
+    }
+}
+
+// Deliveries of every (publisher, sequence number) pair across the members of a
+// shared subscription, to verify each message reached exactly one member
+#[derive(Debug, Clone, Default)]
+pub struct DeliveryLedger {
+    // per publisher topic
+    streams: HashMap<String, LedgerStream>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct LedgerStream {
+    tracker: SequenceTracker,
+    n_unique: u64,
+    n_duplicates: u64,
+    n_beyond_window: u64,
+}
+
+impl DeliveryLedger {
+    pub fn record(&mut self, topic: &str, seq: u64, epoch: u64) {
+        let stream = self.streams.entry(topic.to_string()).or_default();
+        match stream.tracker.record(seq, epoch) {
+            Arrival::Duplicate => { stream.n_duplicates += 1; }
+            Arrival::BeyondWindow => { stream.n_beyond_window += 1; }
+            _ => { stream.n_unique += 1; }
+        }
+    }
+
+    // `sent` holds the publishers' sent count per topic, when they reported one
+    pub fn summary(&self, group: &str, members: &[SubscriberStats], sent: &HashMap<String, u64>) -> SharedSummary {
+        let mut summary = SharedSummary {
+            group: group.to_string(),
+            n_publishers: self.streams.keys().chain(sent.keys()).collect::<HashSet<&String>>().len(),
+            member_messages: members.iter().map(|x| x.n_messages).collect(),
+            ..Default::default()
+        };
+        for stream in self.streams.values() {
+            summary.n_unique += stream.n_unique;
+            summary.n_duplicates += stream.n_duplicates;
+            summary.n_beyond_window += stream.n_beyond_window;
+            summary.n_lost += stream.tracker.n_missing();
+        }
+        // messages sent after the highest one delivered, or on topics nobody received from
+        for (topic, &n_sent) in sent.iter() {
+            let n_seen = self.streams.get(topic).map(|x| x.n_unique + x.tracker.n_missing()).unwrap_or(0);
+            summary.n_lost += n_sent.saturating_sub(n_seen);
+        }
+        let total = summary.member_messages.iter().sum::<u64>();
+        let busiest = summary.member_messages.iter().max().copied().unwrap_or(0);
+        if total > 0 {
+            summary.imbalance = (busiest * summary.member_messages.len() as u64) as f64 / total as f64;
+        }
+        return summary
+    }
+}
+
+// Outcome of a shared subscription iteration
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SharedSummary {
+    pub group: String,
+    pub n_publishers: usize,
+    // distinct messages received by any member
+    pub n_unique: u64,
+    // extra deliveries, to another member or to the same member again
+    pub n_duplicates: u64,
+    // sequence numbers never delivered, per publisher topic
+    pub n_lost: u64,
+    // deliveries from behind the tracked window, unknown whether they were duplicates
+    #[serde(default)]
+    pub n_beyond_window: u64,
+    // messages received by each member, in member order
+    pub member_messages: Vec<u64>,
+    // busiest member's share over an even share, 1.0 is perfectly balanced
+    pub imbalance: f64,
+}
+
+// Delivery of the messages sent around one injected fault, from `margin_ms` before it
+// until `margin_ms` after the clients were back
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FaultReport {
+    pub fault: FaultEvent,
+    pub margin_ms: u64,
+    pub n_messages: u64,
+    pub n_lost: u64,
+    pub n_duplicates: u64,
+    // sent before a subscriber reconnected and received after
+    pub n_recovered: u64,
+}
+
+impl FaultReport {
+    // Sums the subscriber timelines over the window around `fault`, by publisher send time
+    pub fn new(fault: FaultEvent, margin_ms: u64, clients: &[SubscriberStats]) -> Self {
+        let at_us = fault.at.timestamp_micros();
+        let from_us = at_us - (margin_ms * 1000) as i64;
+        let to_us = at_us + ((fault.down_ms + margin_ms) * 1000) as i64;
+        let mut window = TimelineBucket::default();
+        clients.iter().for_each(|x| window.add(&x.timeline.window(from_us, to_us)));
+        return Self {
+            fault: fault,
+            margin_ms: margin_ms,
+            n_messages: window.n_messages,
+            n_lost: window.n_lost(),
+            n_duplicates: window.n_duplicates,
+            n_recovered: window.n_recovered,
+        }
+    }
+}
+
+// Results of one sweep iteration, i.e. one (delay, qos, instancecount) combination
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunResult {
+    pub qos: i32,
+    pub delay: u64,
+    pub instancecount: usize,
+    pub tls: bool,
+    // publishers set the retain flag
+    pub retain: bool,
+    #[serde(default)]
+    pub session: SessionMode,
+    pub mqtt_version: MqttVersion,
+    // subscribers per publisher topic, 1 for the paired topology
+    pub fan_out: usize,
+    // how the subscribers map onto the publisher topics, e.g. "paired" or "fanin"
+    pub topology: String,
+    // message body size in bytes, excluding the sequence header
+    pub payload_size: usize,
+    pub payload_kind: PayloadKind,
+    // rate over time of every publisher client, None in delay mode
+    pub profile: Option<LoadProfile>,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: DateTime<Utc>,
+    pub clients: Vec<SubscriberStats>,
+    // as reported by the controllers, empty if any of them didn't report
+    pub publishers: Vec<PublisherStats>,
+    // $SYS metrics over the measuring window, if the broker publishes them
+    pub broker: Option<BrokerWindow>,
+    // cross-member delivery check, only for shared subscriptions
+    pub shared: Option<SharedSummary>,
+    // faults injected during the iteration, in the order they happened
+    #[serde(default)]
+    pub faults: Vec<FaultReport>,
+}
+
+impl RunResult {
+    pub fn n_messages(&self) -> u64 {
+        return self.clients.iter().map(|x| x.n_messages).sum()
+    }
+
+    pub fn messages_per_second(&self) -> u64 {
+        return self.clients.iter().map(|x| x.messages_per_second).sum()
+    }
+
+    // Messages received at least once, duplicates left out. Per stream counts already leave them
+    // out, shared subscription members count every delivery so the ledger's distinct count is used
+    pub fn n_delivered(&self) -> u64 {
+        if let Some(shared) = self.shared.as_ref() {
+            return shared.n_unique
+        }
+        return self.n_messages()
+    }
+
+    pub fn n_messages_sent(&self) -> Option<u64> {
+        if self.publishers.is_empty() { return None }
+        return Some(self.publishers.iter().map(|x| x.n_messages_sent).sum())
+    }
+
+    pub fn achieved_rate(&self) -> f64 {
+        return self.publishers.iter().map(|x| x.achieved_rate()).sum()
+    }
+
+    // Sum of the publisher clients' mean target rates, None in delay mode
+    pub fn target_rate(&self) -> Option<f64> {
+        if self.profile.is_none() || self.publishers.is_empty() { return None }
+        return Some(self.publishers.iter().filter_map(|x| x.target_rate).sum())
+    }
+
+    pub fn n_bytes(&self) -> u64 {
+        return self.clients.iter().map(|x| x.n_bytes).sum()
+    }
+
+    pub fn n_lost(&self) -> u64 {
+        if let Some(shared) = self.shared.as_ref() {
+            return shared.n_lost
+        }
+        return self.clients.iter().map(|x| x.n_lost).sum()
+    }
+
+    pub fn n_duplicates(&self) -> u64 {
+        if let Some(shared) = self.shared.as_ref() {
+            return shared.n_duplicates
+        }
+        return self.clients.iter().map(|x| x.n_duplicates).sum()
+    }
+
+    pub fn n_reordered(&self) -> u64 {
+        return self.clients.iter().map(|x| x.n_reordered).sum()
+    }
+
+    pub fn n_late(&self) -> u64 {
+        return self.clients.iter().map(|x| x.n_late).sum()
+    }
+
+    pub fn reorder_distance(&self) -> Histogram {
+        let mut merged = Histogram::new();
+        self.clients.iter().for_each(|x| merged.merge(&x.reorder_distance));
+        return merged
+    }
+
+    pub fn timeline(&self) -> Timeline {
+        let mut merged = Timeline::new(self.clients.first().map(|x| x.timeline.bucket_ms).unwrap_or(1));
+        self.clients.iter().for_each(|x| merged.merge(&x.timeline));
+        return merged
+    }
+
+    pub fn latency(&self) -> Histogram {
+        let mut merged = Histogram::new();
+        self.clients.iter().for_each(|x| merged.merge(&x.latency));
+        return merged
+    }
+
+    // Publish acknowledgement latency over all publisher clients
+    pub fn ack_latency(&self) -> Histogram {
+        let mut merged = Histogram::new();
+        self.publishers.iter().for_each(|x| merged.merge(&x.ack_latency));
+        return merged
+    }
+
+    pub fn n_failed_publishes(&self) -> u64 {
+        return self.publishers.iter().map(|x| x.n_failed).sum()
+    }
+
+    pub fn subscriber_connection(&self) -> ConnectionStats {
+        let mut merged = ConnectionStats::default();
+        self.clients.iter().for_each(|x| merged.merge(&x.connection));
+        return merged
+    }
+
+    pub fn publisher_connection(&self) -> ConnectionStats {
+        let mut merged = ConnectionStats::default();
+        self.publishers.iter().for_each(|x| merged.merge(&x.connection));
+        return merged
+    }
+}
+
+// Outcome of a saturation search over one qos/instance count combination, rates in
+// messages per second
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SaturationResult {
+    pub qos: i32,
+    pub instancecount: usize,
+    pub tls: bool,
+    pub retain: bool,
+    pub session: SessionMode,
+    pub mqtt_version: MqttVersion,
+    pub topology: String,
+    pub payload_size: usize,
+    // highest passing rate per publisher client, None if the start rate already failed
+    pub knee_rate: Option<f64>,
+    // knee_rate over all publisher clients
+    pub knee_throughput: Option<f64>,
+    // lowest failing rate per publisher client, None if the maximum rate passed
+    pub failing_rate: Option<f64>,
+    // why the lowest failing rate failed
+    pub limit: Option<String>,
+    pub n_probes: usize,
+}
+
+// Renders one line per sweep iteration, latencies in us
+pub fn results_table(results: &[RunResult]) -> String {
+    let mut table = format!(
+        "{:>8} {:>4} {:>10} {:>5} {:>6} {:>10} {:>5} {:>18} {:>8} {:>10} {:>10} {:>10} {:>8} {:>8} {:>8} {:>10} {:>8} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>12} {:>12} {:>12} {:>9}\n",
+        "delay", "qos", "instances", "tls", "retain", "session", "mqtt", "topology", "payload", "target/s", "sent/s", "messages", "msg/s", "lost", "dup", "reordered", "late",
+        "reconnects", "recovered", "p50_us", "p99_us", "p99.9_us", "max_us", "broker_rx", "broker_tx", "broker_cli", "imbalance"
+    );
+    for result in results {
+        let latency = result.latency().summary();
+        let subscriber_connection = result.subscriber_connection();
+        let publisher_connection = result.publisher_connection();
+        table += &format!(
+            "{:>8} {:>4} {:>10} {:>5} {:>6} {:>10} {:>5} {:>18} {:>8} {:>10} {:>10} {:>10} {:>8} {:>8} {:>8} {:>10} {:>8} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>12} {:>12} {:>12} {:>9}\n",
+            result.delay, result.qos, result.instancecount, result.tls, result.retain, result.session.to_string(), result.mqtt_version.to_string(),
+            result.topology, result.payload_size,
+            result.target_rate().map(|x| format!("{:.0}", x)).unwrap_or("NA".to_string()),
+            if result.publishers.is_empty() { "NA".to_string() } else { format!("{:.0}", result.achieved_rate()) },
+            result.n_messages(), result.messages_per_second(),
+            result.n_lost(), result.n_duplicates(), result.n_reordered(), result.n_late(),
+            subscriber_connection.n_reconnects + publisher_connection.n_reconnects,
+            subscriber_connection.n_recovered + publisher_connection.n_recovered,
+            latency.p50, latency.p99, latency.p999, latency.max,
+            format_metric(result.broker.and_then(|x| x.messages_received())),
+            format_metric(result.broker.and_then(|x| x.messages_sent())),
+            format_metric(result.broker.and_then(|x| x.peak_clients_connected)),
+            result.shared.as_ref().map(|x| format!("{:.2}", x.imbalance)).unwrap_or("NA".to_string())
+        );
+    }
+    return table
+}
+
+// Renders one line per saturation search
+pub fn saturation_table(saturations: &[SaturationResult]) -> String {
+    let mut table = format!(
+        "{:>4} {:>10} {:>5} {:>6} {:>10} {:>5} {:>18} {:>8} {:>12} {:>14} {:>12} {:>7}  {}\n",
+        "qos", "instances", "tls", "retain", "session", "mqtt", "topology", "payload", "knee/s", "knee_total/s", "failing/s", "probes", "limit"
+    );
+    let rate = |x: Option<f64>| x.map(|x| format!("{:.0}", x)).unwrap_or("NA".to_string());
+    for saturation in saturations {
+        table += &format!(
+            "{:>4} {:>10} {:>5} {:>6} {:>10} {:>5} {:>18} {:>8} {:>12} {:>14} {:>12} {:>7}  {}\n",
+            saturation.qos, saturation.instancecount, saturation.tls, saturation.retain, saturation.session.to_string(),
+            saturation.mqtt_version.to_string(),
+            saturation.topology, saturation.payload_size,
+            rate(saturation.knee_rate), rate(saturation.knee_throughput), rate(saturation.failing_rate),
+            saturation.n_probes, saturation.limit.clone().unwrap_or("max rate reached".to_string())
+        );
+    }
+    return table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record_all(tracker: &mut SequenceTracker, seqs: &[u64]) -> Vec<Arrival> {
+        return seqs.iter().map(|&x| tracker.record(x, 1)).collect()
+    }
+
+    #[test]
+    fn tracker_counts_in_order_and_gaps() {
+        let mut tracker = SequenceTracker::default();
+        assert_eq!(record_all(&mut tracker, &[0, 1, 2]), vec![Arrival::InOrder; 3]);
+        assert_eq!(tracker.record(5, 1), Arrival::Gap { skipped: 2 });
+        assert_eq!(tracker.record(6, 1), Arrival::InOrder);
+        assert_eq!(tracker.n_missing(), 2);
+
+        // a stream joined mid-way counts everything before its first message as missing
+        let mut tracker = SequenceTracker::default();
+        assert_eq!(tracker.record(3, 1), Arrival::Gap { skipped: 3 });
+    }
+
+    #[test]
+    fn tracker_fills_gaps_with_late_arrivals_and_spots_duplicates() {
+        let mut tracker = SequenceTracker::default();
+        record_all(&mut tracker, &[0, 1, 4]);
+        assert_eq!(tracker.n_missing(), 2);
+        assert_eq!(tracker.record(2, 1), Arrival::Late { distance: 2 });
+        assert_eq!(tracker.record(2, 1), Arrival::Duplicate);
+        assert_eq!(tracker.record(4, 1), Arrival::Duplicate);
+        assert_eq!(tracker.record(0, 1), Arrival::Duplicate);
+        assert_eq!(tracker.n_missing(), 1);
+        assert_eq!(tracker.record(3, 1), Arrival::Late { distance: 1 });
+        assert_eq!(tracker.n_missing(), 0);
+        assert_eq!(tracker.record(5, 1), Arrival::InOrder);
+    }
+
+    #[test]
+    fn tracker_keeps_its_window_for_arrivals_behind_it() {
+        let mut tracker = SequenceTracker::default();
+        tracker.record(0, 1);
+        let last = SEQUENCE_WINDOW * 3;
+        assert_eq!(tracker.record(last, 1), Arrival::Gap { skipped: last - 1 });
+        // slid out of the window, neither taken as late nor moving the window back
+        assert_eq!(tracker.record(5, 1), Arrival::BeyondWindow);
+        assert_eq!(tracker.n_missing(), last - 1);
+        assert_eq!(tracker.record(last + 1, 1), Arrival::InOrder);
+        assert_eq!(tracker.n_missing(), last - 1);
+        // a redelivery of a message received long ago doesn't lower the loss either
+        assert_eq!(tracker.record(0, 1), Arrival::BeyondWindow);
+        assert_eq!(tracker.n_missing(), last - 1);
+
+        let mut source = SourceStats::new("counter/0/1/0");
+        source.record(Arrival::BeyondWindow, 1000);
+        assert_eq!((source.n_messages, source.n_late, source.n_beyond_window), (0, 0, 1));
+        // still inside the window
+        assert_eq!(tracker.record(last - 10, 1), Arrival::Late { distance: 11 });
+        assert_eq!(tracker.record(last - 10, 1), Arrival::Duplicate);
+    }
+
+    #[test]
+    fn tracker_wraps_around_at_the_end_of_the_counter() {
+        // as if the counter had been running for a while
+        let mut tracker = SequenceTracker { epoch: Some(1), next: u64::MAX - 2, ..Default::default() };
+        let n_missing = tracker.n_missing();
+        assert_eq!(record_all(&mut tracker, &[u64::MAX - 2, u64::MAX - 1, u64::MAX, 0, 1]), vec![Arrival::InOrder; 5]);
+        assert_eq!(tracker.record(3, 1), Arrival::Gap { skipped: 1 });
+        assert_eq!(tracker.record(u64::MAX, 1), Arrival::Duplicate);
+        assert_eq!(tracker.record(2, 1), Arrival::Late { distance: 1 });
+        assert_eq!(tracker.n_missing(), n_missing);
+    }
+
+    #[test]
+    fn tracker_restarts_only_on_a_newer_epoch() {
+        let mut tracker = SequenceTracker::default();
+        let seqs = (0..1_000).collect::<Vec<u64>>();
+        record_all(&mut tracker, &seqs);
+        // a fresh counter well inside the old window isn't mistaken for duplicates
+        assert_eq!(tracker.record(0, 2), Arrival::Restart);
+        assert_eq!(tracker.record(1, 2), Arrival::InOrder);
+        assert_eq!(tracker.record(3, 2), Arrival::Gap { skipped: 1 });
+        assert_eq!(tracker.n_missing(), 1);
+        // leftovers of the previous run neither restart the stream nor fill its gaps
+        assert_eq!(tracker.record(999, 1), Arrival::Stale);
+        assert_eq!(tracker.record(4, 2), Arrival::InOrder);
+        assert_eq!(tracker.n_missing(), 1);
+    }
+
+    fn run_result(clients: Vec<SubscriberStats>, shared: Option<SharedSummary>) -> RunResult {
+        return RunResult {
+            qos: 1, delay: 0, instancecount: 1, tls: false, retain: false,
+            session: SessionMode::default(), mqtt_version: MqttVersion::default(),
+            fan_out: 1, topology: "paired".to_string(), payload_size: 0, payload_kind: PayloadKind::default(), profile: None,
+            started_at: Utc::now(), ended_at: Utc::now(),
+            clients, publishers: Vec::new(), broker: None, shared, faults: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn delivered_leaves_duplicates_out_on_both_paths() {
+        // per stream counts exclude the duplicates already
+        let mut client = SubscriberStats::new(0, 1, 0);
+        client.n_messages = 90;
+        client.n_duplicates = 10;
+        client.n_lost = 10;
+        assert_eq!(run_result(vec![client.clone()], None).n_delivered(), 90);
+
+        // shared members count every delivery, the ledger counts distinct messages
+        client.n_messages = 100;
+        let shared = SharedSummary { n_unique: 90, n_duplicates: 10, n_lost: 10, ..Default::default() };
+        assert_eq!(run_result(vec![client], Some(shared)).n_delivered(), 90);
+    }
+
+    #[test]
+    fn histogram_serializes_only_non_empty_buckets() {
+        let mut histogram = Histogram::new();
+        for value in [0, 5, 5, 1_000, 123_456_789, u64::MAX] {
+            histogram.record(value);
+        }
+        let json = serde_json::to_string(&histogram).unwrap();
+        assert!(json.len() < 300, "{}", json);
+        assert_eq!(serde_json::from_str::<Histogram>(&json).unwrap(), histogram);
+
+        let empty = serde_json::to_string(&Histogram::new()).unwrap();
+        assert_eq!(serde_json::from_str::<Histogram>(&empty).unwrap(), Histogram::new());
+    }
+
+    #[test]
+    fn histogram_rejects_inconsistent_buckets() {
+        let out_of_range = format!(r#"{{"counts":[[{},1]],"total":1,"sum":1,"min":1,"max":1}}"#, N_BUCKETS);
+        assert!(serde_json::from_str::<Histogram>(&out_of_range).is_err());
+        let wrong_total = r#"{"counts":[[1,1]],"total":2,"sum":1,"min":1,"max":1}"#;
+        assert!(serde_json::from_str::<Histogram>(wrong_total).is_err());
+    }
+
+    #[test]
+    fn histogram_percentiles_stay_within_bucket_error() {
+        let mut histogram = Histogram::new();
+        (1..=10_000u64).for_each(|x| histogram.record(x));
+        assert_eq!(histogram.len(), 10_000);
+        assert_eq!(histogram.min(), 1);
+        assert_eq!(histogram.max(), 10_000);
+        assert_eq!(histogram.mean(), 5_000);
+        let p50 = histogram.value_at_percentile(50.0);
+        assert!((4_900..=5_100).contains(&p50), "{}", p50);
+        assert_eq!(histogram.value_at_percentile(100.0), 10_000);
+
+        let mut merged = Histogram::new();
+        merged.merge(&histogram);
+        merged.merge(&histogram);
+        assert_eq!(merged.len(), 20_000);
+        assert_eq!(merged.value_at_percentile(50.0), p50);
+    }
+}
@@ -2,232 +2,505 @@ use chrono::DateTime;
 use chrono::Utc;
 use futures::future;
 use paho_mqtt::Message;
-use tokio::{sync::Mutex, time::sleep};
-use std::{sync::Arc, time::Duration, error::Error};
-use paho_mqtt::{AsyncClient, AsyncReceiver, ConnectOptionsBuilder, CreateOptionsBuilder};
+use tokio::{sync::Mutex, time::timeout};
+use std::{sync::Arc, time::Duration, error::Error, collections::HashMap, fmt, str::FromStr};
+use paho_mqtt::{AsyncClient, AsyncReceiver, ConnectOptions};
 
 use crate::log;
 use crate::write_log;
+use crate::connection::{ConnectionConfig, ConnectionEvents, SessionMode, describe_error};
+use crate::fault::{FaultEvent, FaultSpec, FaultTarget, spawn_client_faults};
+use crate::payload::{now_micros, read_message};
+use crate::publisher::publisher_topic;
+use crate::stats::{Arrival, DeliveryLedger, PublisherStats, SequenceTracker, SourceStats, SubscriberStats, Timeline};
+use crate::broker::BrokerMetrics;
+
+// How long a client waits on its stream before re-checking the stop flag
+const RECV_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+// How the subscriber clients map onto the publisher topics
+#[derive(Debug, Clone, PartialEq)]
+pub enum SubscriptionMode {
+    // client i subscribes to the topic of publisher i only
+    Paired,
+    // all clients join one shared subscription over every publisher topic
+    Shared { group: String },
+    // `fan_out` consecutive clients subscribe to each publisher topic, with `wildcard`
+    // every client subscribes to all publisher topics instead
+    FanOut { fan_out: usize, wildcard: bool },
+    // a single client consumes every publisher topic through "counter/#"
+    FanIn,
+}
+
+impl SubscriptionMode {
+    pub fn topic_filter(&self, client_index: usize, qos: i32, delay: u64) -> String {
+        return match self {
+            SubscriptionMode::Paired => publisher_topic(client_index, qos, delay),
+            SubscriptionMode::Shared { group } => format!("$share/{}/counter/+/{}/{}", group, qos, delay),
+            SubscriptionMode::FanOut { wildcard: true, .. } => format!("counter/+/{}/{}", qos, delay),
+            SubscriptionMode::FanOut { fan_out, wildcard: false } => {
+                publisher_topic(client_index / (*fan_out).max(1), qos, delay)
+            }
+            SubscriptionMode::FanIn => "counter/#".to_string(),
+        }
+    }
+
+    // Subscribers per publisher topic, 1 for the other topologies
+    pub fn fan_out(&self) -> usize {
+        return match self {
+            SubscriptionMode::FanOut { fan_out, .. } => *fan_out,
+            _ => 1,
+        }
+    }
+}
+
+impl fmt::Display for SubscriptionMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            SubscriptionMode::Paired => write!(f, "paired"),
+            SubscriptionMode::Shared { group } => write!(f, "shared:{}", group),
+            SubscriptionMode::FanOut { fan_out, wildcard: false } => write!(f, "fanout:{}", fan_out),
+            SubscriptionMode::FanOut { fan_out, wildcard: true } => write!(f, "fanout:{}:wildcard", fan_out),
+            SubscriptionMode::FanIn => write!(f, "fanin"),
+        }
+    }
+}
+
+// Parses the Display form back, e.g. "fanout:4:wildcard"
+impl FromStr for SubscriptionMode {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let fields = spec.split(':').collect::<Vec<&str>>();
+        let mode = match fields.as_slice() {
+            ["paired"] => SubscriptionMode::Paired,
+            ["shared", group] if !group.is_empty() => SubscriptionMode::Shared { group: group.to_string() },
+            ["fanout", fan_out] | ["fanout", fan_out, "wildcard"] => SubscriptionMode::FanOut {
+                fan_out: fan_out.parse::<usize>().ok().filter(|&x| x > 0)
+                    .ok_or(format!("invalid fan-out in topology \"{}\"", spec))?,
+                wildcard: fields.len() == 3,
+            },
+            ["fanin"] => SubscriptionMode::FanIn,
+            _ => {
+                return Err(format!(
+                    "unknown topology \"{}\", expected paired, shared:<group>, fanout:<n>[:wildcard] or fanin", spec
+                ))
+            }
+        };
+        return Ok(mode)
+    }
+}
+
+// Sequence state of one publisher topic within a subscriber client
+struct StreamState {
+    tracker: SequenceTracker,
+    last_in_order: Option<DateTime<Utc>>,
+    stats: SourceStats,
+}
 
 pub struct Subscriber {
     clients: Arc<Mutex<Vec<AsyncClient>>>,
+    events: Vec<Arc<ConnectionEvents>>,
+    // kept to connect the clients again after an injected fault
+    connect_options: ConnectOptions,
     target_qos: i32, target_delay: u64,
+    mode: SubscriptionMode,
+    timeline_ms: u64,
+    // late arrivals further behind than this many sequence numbers count as late, not reordered
+    reorder_window: u64,
+    session_mode: SessionMode,
     pub make_graceful_stop: Arc<Mutex<bool>>,
+    // filled by all members in shared mode
+    pub ledger: Arc<Mutex<DeliveryLedger>>,
+    fault_specs: Vec<FaultSpec>,
+    // every fault injected into these clients so far
+    pub faults: Arc<Mutex<Vec<FaultEvent>>>,
 }
 
 impl Subscriber {
     pub async fn connect(
-        host_uri: &str, creds: Option<(&str, &str)>, 
-        n_instances: usize, delay: u64, qos: i32
+        connection: &ConnectionConfig,
+        n_instances: usize, delay: u64, qos: i32,
+        mode: SubscriptionMode, timeline_ms: u64, reorder_window: u64,
+        fault_specs: Vec<FaultSpec>
     ) -> Result<Self, String> {
         if n_instances > 0 {
+            let connect_options = connection.connect_options()?.finalize();
             let mut connect_all = Vec::new();
 
             for i in 0..n_instances {
+                let connect_options = connect_options.clone();
                 connect_all.push(async move {
                     let client = AsyncClient::new(
-                        CreateOptionsBuilder::new()
-                        .client_id(format!("subscriber_{}", i))
-                        .server_uri(host_uri)
-                        .finalize()
-                    ).unwrap();
-                    if let Some((username, password)) = creds {
-                        client.connect(
-                            ConnectOptionsBuilder::new()
-                            .user_name(username)
-                            .password(password)
-                            .finalize()
-                        ).await.unwrap();
-                    } else {
-                        client.connect(
-                            ConnectOptionsBuilder::new().finalize()
-                        ).await.unwrap();
-                    }
-                    return client
+                        connection.create_options(&format!("subscriber_{}", i))
+                    ).map_err(|e| e.to_string())?;
+                    client.connect(connect_options).await.map_err(|e| describe_error(&e))?;
+                    return Ok::<AsyncClient, String>(client)
                 });
             }
     
+            let clients = future::try_join_all(connect_all).await?;
+            let session_mode = connection.session.mode;
+            let events = clients.iter().enumerate().map(|(i, client)| {
+                // a clean session loses its subscription with the connection
+                let resubscribe_filter = mode.topic_filter(i, qos, delay);
+                ConnectionEvents::watch(client, move |client| {
+                    if session_mode == SessionMode::Clean {
+                        let _ = client.subscribe(&resubscribe_filter, qos);
+                    }
+                })
+            }).collect();
             return Ok(Self {
-                clients: Arc::new(Mutex::new(
-                    future::join_all(connect_all).await
-                )),
+                clients: Arc::new(Mutex::new(clients)),
+                events: events,
+                connect_options: connect_options,
                 target_qos: qos,
                 target_delay: delay,
+                mode: mode,
+                timeline_ms: timeline_ms,
+                reorder_window: reorder_window,
+                session_mode: connection.session.mode,
                 make_graceful_stop: Arc::new(Mutex::new(false)),
+                ledger: Arc::new(Mutex::new(DeliveryLedger::default())),
+                fault_specs: fault_specs,
+                faults: Arc::new(Mutex::new(Vec::new())),
             });
         } else {
             return Err("".to_string())
         }
     }
 
-    pub async fn start(&mut self) -> Vec<(u64, u64, u64, u64, u64)>{
+    pub async fn start(&mut self) -> Vec<SubscriberStats> {
         let mut async_instances = Vec::new();
 
         for i in 0..self.clients.lock().await.len() {
             let make_graceful_stop = Arc::clone(&self.make_graceful_stop);
 
             let clients = Arc::clone(&self.clients);
+            let ledger = Arc::clone(&self.ledger);
+            let mode = self.mode.clone();
+            let timeline_ms = self.timeline_ms;
+            let reorder_window = self.reorder_window;
+            let session_mode = self.session_mode;
+            let events = Arc::clone(&self.events[i]);
 
             let qos = self.target_qos;
             let delay = self.target_delay;
             
             async_instances.push(async move {
-                let resp_stream = (*clients.lock().await)[i].get_stream(32);
+                // unbounded, paho drops whatever doesn't fit a bounded stream and that would be counted as
+                // broker loss, a consumer falling behind shows up as latency instead
+                let resp_stream = (*clients.lock().await)[i].get_stream(None);
 
-                let mut delays: Vec<u64> = Vec::new();
-                let mut total_runtime: u64 = 0;
-                let mut total_n_messages: u64 = 0;
-                let mut out_of_order_counter: u64 = 0;
-                let mut out_of_order_misses: Vec<u64> = Vec::new();
+                let mut stats = SubscriberStats::new(i, qos, delay);
+                stats.timeline = Timeline::new(timeline_ms);
+                let mut streams: HashMap<String, StreamState> = HashMap::new();
 
-                (*clients.lock().await)[i].subscribe(
-                    format!("counter/{}/{}/{}", i, qos, delay), qos
-                ).await.unwrap();
+                let shared = matches!(mode, SubscriptionMode::Shared { .. });
+                let topic_filter = mode.topic_filter(i, qos, delay);
+                if let Err(msg) = (*clients.lock().await)[i].subscribe(&topic_filter, qos).await {
+                    write_log!("Subscriber client {} failed to subscribe. [reason={}]\n", i, describe_error(&msg));
+                }
+                let mut n_recovered: u64 = 0;
 
-                let mut iter: u64 = 0;
-                let mut time_start: Option<DateTime<Utc>> = None;
                 let mut delay_counter: Option<DateTime<Utc>> = None;
                 while !(*make_graceful_stop.lock().await) {
-                    if let Ok(Some(resp_msg)) = resp_stream.recv().await {
+                    // bounded wait so a silent stream doesn't block the graceful stop
+                    if let Ok(Ok(Some(resp_msg))) = timeout(RECV_POLL_INTERVAL, resp_stream.recv()).await {
+                        // replayed on subscribe, left over from an earlier iteration
+                        if resp_msg.retained() {
+                            continue;
+                        }
                         let time_now = Utc::now();
-                        if let Ok(counter) = resp_msg.payload_str().parse::<u64>() {
-                            if time_start.is_some() {
-                                total_runtime += time_now.signed_duration_since(time_start.unwrap()).num_milliseconds() as u64;
+                        let received_at_us = now_micros();
+                        // the header travels in the payload under v3.1.1 but in properties under v5, only the
+                        // body is counted so both versions report the same bytes
+                        if let Some((header, body)) = read_message(&resp_msg) {
+                            let n_body_bytes = body.len() as u64;
+                            let counter = header.seq;
+                            let latency_us = header.latency_us(received_at_us);
+                            stats.latency.record(latency_us);
+                            if stats.first_message_at.is_none() {
+                                stats.first_message_at = Some(time_now);
                             }
-                            if iter < counter {
-                                out_of_order_counter += 1;
-                                
-                                let missings = (iter..counter).collect::<Vec<u64>>();
-                                out_of_order_misses.extend(missings);
-                                
-                                total_n_messages += 1;
-                                iter = counter + 1;
-                                delay_counter = None;
-                                time_start = Some(Utc::now());
-                            } else if iter > counter {
-                                if out_of_order_misses.contains(&counter) {
-                                    out_of_order_misses.retain(|&x| {x != counter});
-                                    total_n_messages += 1;
-                                    iter += 1;
-                                    delay_counter = None;
-                                    time_start = Some(Utc::now());
+                            stats.last_message_at = Some(time_now);
+                            // held by the broker or in flight across the outage
+                            let recovered = events.reconnected_at_us().is_some_and(|x| header.sent_at_us < x);
+
+                            if shared {
+                                // a member only sees part of every stream, sequence checks are done across members
+                                ledger.lock().await.record(resp_msg.topic(), counter, header.epoch);
+                                stats.timeline.record(header.sent_at_us, latency_us, Arrival::InOrder, recovered);
+                                if let Some(previous) = delay_counter {
+                                    stats.inter_arrival.record(time_now.signed_duration_since(previous).num_microseconds().unwrap_or(0).max(0) as u64);
                                 }
+                                stats.n_messages += 1;
+                                stats.n_bytes += n_body_bytes;
+                                if recovered { n_recovered += 1; }
+                                delay_counter = Some(time_now);
                             } else {
-                                if delay_counter.is_some() {
-                                    delays.push(time_now.signed_duration_since(delay_counter.unwrap()).num_milliseconds() as u64);
+                                // every publisher topic carries its own sequence
+                                let stream = streams.entry(resp_msg.topic().to_string()).or_insert_with(|| StreamState {
+                                    tracker: SequenceTracker::default(),
+                                    last_in_order: None,
+                                    stats: SourceStats::new(resp_msg.topic()),
+                                });
+                                stream.stats.latency.record(latency_us);
+                                // counted per topic, the client totals are summed up at the end
+                                let arrival = stream.tracker.record(counter, header.epoch);
+                                stream.stats.record(arrival, reorder_window);
+                                stats.timeline.record(header.sent_at_us, latency_us, arrival, recovered);
+                                if arrival != Arrival::Duplicate && arrival != Arrival::BeyondWindow {
+                                    stats.n_bytes += n_body_bytes;
+                                    if recovered { n_recovered += 1; }
+                                }
+                                match arrival {
+                                    Arrival::InOrder => {
+                                        if let Some(previous) = stream.last_in_order {
+                                            stats.inter_arrival.record(time_now.signed_duration_since(previous).num_microseconds().unwrap_or(0).max(0) as u64);
+                                        }
+                                        stream.last_in_order = Some(time_now);
+                                    }
+                                    Arrival::Duplicate | Arrival::BeyondWindow => {}
+                                    _ => { stream.last_in_order = None; }
                                 }
-                                total_n_messages += 1;
-                                iter += 1;
-                                delay_counter = Some(Utc::now());
-                                time_start = Some(Utc::now());
                             }
                         }
                     }
                 }
 
+                if session_mode == SessionMode::Persistent {
+                    // otherwise the broker keeps queueing for the next iteration's client of the same id
+                    let _ = (*clients.lock().await)[i].unsubscribe(&topic_filter).await;
+                }
                 (*clients.lock().await)[i].disconnect(None).await;
+                stats.connection = events.stats(n_recovered);
 
-                delays.sort();
-                let total_delays = delays.iter().sum::<u64>();
-                let mean_delay = if delays.is_empty() { 0 } else  { total_delays / delays.len() as u64 };
-                let median_delay = if delays.is_empty() { 0 } else { delays[delays.len() / 2] };
-                let n_out_of_order_misses = out_of_order_misses.len();
+                if !shared {
+                    stats.sources = streams.into_values().map(|mut x| {
+                        x.stats.n_lost = x.tracker.n_missing();
+                        x.stats
+                    }).collect();
+                    stats.sources.sort_by(|a, b| a.topic.cmp(&b.topic));
+                    stats.sum_sources();
+                }
+                let window_ms = stats.window_ms();
+                stats.messages_per_second = if window_ms != 0 { (stats.n_messages * 1000) / window_ms } else { 0 };
 
                 write_log!(r#"Subscriber client {} terminated. [graceful stop=true] [
-    mean_delays={}ms,
-    median_delays={}ms,
+    delays_us=[{}],
+    latency_us=[{}],
     messages_per_second={},
     total_number_of_messages={},
+    total_number_of_bytes={},
     out_of_order_counter={},
     n_out_of_order_missings={},
+    n_reordered={},
+    n_late={},
+    n_beyond_window={},
+    reorder_distance=[{}],
+    n_duplicates={},
+    n_restarts={},
+    n_disconnects={},
+    n_reconnects={},
+    downtime_ms={},
+    n_recovered={},
+    n_sources={},
+    window_ms={},
     target_qos={},
     target_delay={},
 ]
                     "#, i, 
-                    if delays.is_empty() { "NA".to_string() } else { mean_delay.to_string() },
-                    if delays.is_empty() { "NA".to_string() } else { median_delay.to_string() },
-                    if total_runtime != 0 { (total_n_messages * 1000) / total_runtime } else { 0 }, 
-                    total_n_messages, 
-                    out_of_order_counter, 
-                    n_out_of_order_misses,
+                    stats.inter_arrival.summary(),
+                    stats.latency.summary(),
+                    stats.messages_per_second, 
+                    stats.n_messages, 
+                    stats.n_bytes,
+                    stats.n_out_of_order, 
+                    stats.n_lost,
+                    stats.n_reordered,
+                    stats.n_late,
+                    stats.n_beyond_window,
+                    stats.reorder_distance.summary(),
+                    stats.n_duplicates,
+                    stats.n_restarts,
+                    stats.connection.n_disconnects,
+                    stats.connection.n_reconnects,
+                    stats.connection.downtime_ms,
+                    stats.connection.n_recovered,
+                    stats.n_sources,
+                    window_ms,
                     qos,
                     delay
                 );
+                if stats.sources.len() > 1 {
+                    for source in stats.sources.iter() {
+                        write_log!(r#"Subscriber client {} source {}. [
+    latency_us=[{}],
+    total_number_of_messages={},
+    out_of_order_counter={},
+    n_out_of_order_missings={},
+    n_reordered={},
+    n_late={},
+    n_beyond_window={},
+    n_duplicates={},
+    n_restarts={},
+]
+                        "#, i, source.topic, source.latency.summary(), source.n_messages,
+                        source.n_out_of_order, source.n_lost, source.n_reordered, source.n_late, source.n_beyond_window,
+                        source.n_duplicates, source.n_restarts);
+                    }
+                }
 
-                return (
-                    mean_delay, 
-                    if total_runtime != 0 { (total_n_messages * 1000) / total_runtime } else { 0 }, 
-                    total_n_messages, 
-                    out_of_order_counter, 
-                    n_out_of_order_misses as u64
-                )
+                return stats
             });
         }
         write_log!(r#"Subscriber clients 0..{} ready. [
     target_qos={},
     target_delay={},
+    mode={:?},
 ]
-        "#, async_instances.len() - 1, self.target_qos, self.target_delay);
+        "#, async_instances.len() - 1, self.target_qos, self.target_delay, self.mode);
 
-        return future::join_all(async_instances).await
+        let fault_tasks = spawn_client_faults(
+            &self.fault_specs, FaultTarget::Subscribers, &self.clients, &self.events, 0, &self.connect_options, &self.faults
+        );
+        let stats = future::join_all(async_instances).await;
+        for task in fault_tasks {
+            task.abort();
+        }
+        return stats
     }
 }
 
-// Inapplicable for now
+// Messages sent on every publisher topic, as reported by the publishers
+pub fn sent_per_topic(publishers: &[PublisherStats], qos: i32, delay: u64) -> HashMap<String, u64> {
+    return publishers.iter()
+        .map(|x| (publisher_topic(x.client_index, qos, delay), x.n_messages_sent))
+        .collect()
+}
+
+// Settles the loss of every stream a client subscribed to against what its publisher sent, which
+// also covers messages lost after the last delivery and streams that never delivered anything.
+// Shared subscriptions are settled across members by the ledger instead
+pub fn reconcile(clients: &mut [SubscriberStats], mode: &SubscriptionMode, publishers: &[PublisherStats]) {
+    for client in clients.iter_mut() {
+        let expected = match mode {
+            SubscriptionMode::Shared { .. } => { continue; }
+            SubscriptionMode::Paired => publishers.iter()
+                .filter(|x| x.client_index == client.client_index).collect::<Vec<&PublisherStats>>(),
+            SubscriptionMode::FanOut { fan_out, wildcard: false } => publishers.iter()
+                .filter(|x| x.client_index == client.client_index / (*fan_out).max(1)).collect(),
+            SubscriptionMode::FanOut { wildcard: true, .. } | SubscriptionMode::FanIn => publishers.iter().collect(),
+        };
+        if expected.is_empty() { continue; }
+
+        for publisher in expected {
+            let topic = publisher_topic(publisher.client_index, client.qos, client.delay);
+            let n_sent = publisher.n_messages_sent;
+            if !client.sources.iter().any(|x| x.topic == topic) {
+                client.sources.push(SourceStats::new(&topic));
+            }
+            let source = client.sources.iter_mut().find(|x| x.topic == topic).unwrap();
+            if source.n_messages > n_sent {
+                write_log!("Subscriber client {} received more distinct messages than were sent. [topic={}] [received={}] [sent={}]\n",
+                    client.client_index, topic, source.n_messages, n_sent);
+            }
+            source.n_sent = Some(n_sent);
+            source.n_lost = n_sent.saturating_sub(source.n_messages);
+        }
+        client.sources.sort_by(|a, b| a.topic.cmp(&b.topic));
+        client.sum_sources();
+    }
+}
+
+// Collects broker-side metrics published under $SYS/#
 pub struct SysSubscriber {
-    client: AsyncClient
+    client: AsyncClient,
+    pub metrics: Arc<Mutex<BrokerMetrics>>,
+    pub make_graceful_stop: Arc<Mutex<bool>>,
 }
 
 impl SysSubscriber {
-    pub async fn connect(host_uri: &str, creds: Option<(&str, &str)>) -> Result<Self, String> {
+    pub async fn connect(connection: &ConnectionConfig) -> Result<Self, String> {
+        let client = AsyncClient::new(
+            connection.create_options("SYS-subscriber")
+        ).map_err(|e| e.to_string())?;
+        client.connect(
+            connection.connect_options()?.finalize()
+        ).await.map_err(|e| describe_error(&e))?;
 
         return Ok(Self {
-            client: {
-                let client = AsyncClient::new(
-                    CreateOptionsBuilder::new()
-                    .client_id("SYS-subscriber".to_string())
-                    .server_uri(host_uri)
-                    .finalize()
-                ).unwrap();
-                if let Some((username, password)) = creds {
-                    client.connect(
-                        ConnectOptionsBuilder::new()
-                        .user_name(username)
-                        .password(password)
-                        .finalize()
-                    ).await.unwrap();
-                } else {
-                    client.connect(
-                        ConnectOptionsBuilder::new().finalize()
-                    ).await.unwrap();
-                }
-                client
-            }
+            client: client,
+            metrics: Arc::new(Mutex::new(BrokerMetrics::default())),
+            make_graceful_stop: Arc::new(Mutex::new(false)),
         })
     }
 
     pub async fn start(&mut self) {
-        let mut resp_stream: AsyncReceiver<Option<Message>> = self.client.get_stream(512);
+        let resp_stream: AsyncReceiver<Option<Message>> = self.client.get_stream(512);
+
+        if let Err(msg) = self.client.subscribe("$SYS/#", 0).await {
+            write_log!("SYS subscriber failed to subscribe. [reason={}]\n", describe_error(&msg));
+            return
+        }
 
-        self.client.subscribe("$SYS", 2).await.unwrap();
+        write_log!("SYS subscriber initiated.\n");
 
-        write_log!("SYS subscriber initiated.");
-        println!("SYS subscriber initiated.");
+        while !(*self.make_graceful_stop.lock().await) {
+            if let Ok(Ok(Some(resp_msg))) = timeout(RECV_POLL_INTERVAL, resp_stream.recv()).await {
+                self.metrics.lock().await.update(resp_msg.topic(), &resp_msg.payload_str());
+            }
+        }
 
-        // This function is unfinished
-        todo!();
+        let _ = self.client.disconnect(None).await;
 
-        while let Ok(Some(resp_msg)) = resp_stream.recv().await {
-            println!(r#"Message received from SYS. [
-    topic={},
-    payload={},
-    qos={},
-]
-        "#, resp_msg.topic(), resp_msg.payload_str(), resp_msg.qos());
+        write_log!("SYS subscriber terminated. [graceful stop=true]\n");
+    }
+}
 
-            // match resp_msg.topic() {
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            // }
+    #[test]
+    fn shared_topology_round_trips_through_its_compact_form() {
+        for spec in ["paired", "shared:group1"] {
+            assert_eq!(spec.parse::<SubscriptionMode>().unwrap().to_string(), spec);
         }
+        for spec in ["", "shared", "shared:", "broadcast"] {
+            assert!(spec.parse::<SubscriptionMode>().is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn shared_topology_subscribes_to_every_publisher_through_the_group() {
+        assert_eq!(SubscriptionMode::Paired.topic_filter(3, 1, 8), "counter/3/1/8");
+        assert_eq!(SubscriptionMode::Shared { group: "g".to_string() }.topic_filter(3, 1, 8), "$share/g/counter/+/1/8");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn fan_out_topology_round_trips_through_its_compact_form() {
+        for spec in ["fanout:4", "fanout:4:wildcard"] {
+            assert_eq!(spec.parse::<SubscriptionMode>().unwrap().to_string(), spec);
+        }
+        for spec in ["fanout", "fanout:0", "fanout:x", "fanout:2:all"] {
+            assert!(spec.parse::<SubscriptionMode>().is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn fan_out_subscribers_share_their_publisher_topic() {
+        let fan_out = SubscriptionMode::FanOut { fan_out: 2, wildcard: false };
+        assert_eq!((0..4).map(|i| fan_out.topic_filter(i, 0, 0)).collect::<Vec<String>>(),
+            vec!["counter/0/0/0", "counter/0/0/0", "counter/1/0/0", "counter/1/0/0"]);
+        assert_eq!(SubscriptionMode::FanOut { fan_out: 2, wildcard: true }.topic_filter(1, 2, 0), "counter/+/2/0");
+    }
+
+    #[test]
+    fn fan_in_subscriber_aggregates_every_topic() {
+        assert_eq!("fanin".parse::<SubscriptionMode>().unwrap(), SubscriptionMode::FanIn);
+        assert!("fanin:1".parse::<SubscriptionMode>().is_err());
+        assert_eq!(SubscriptionMode::FanIn.topic_filter(0, 2, 0), "counter/#");
+    }
+}